@@ -0,0 +1,303 @@
+use std::cmp;
+use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
+
+use types::*;
+use conflict::ConflictPolicy;
+use export;
+
+/// Per-mount options, set on the command line with `-o key=value,key=value`.
+#[derive (Debug, Clone)]
+pub struct MountConfig {
+    // seconds a dirty file must go unmodified before it is uploaded
+    pub upload_delay: u64,
+    // ceiling, in seconds, of the backoff between failed upload attempts
+    pub upload_max_backoff: u64,
+    // bytes sent per request of a resumable upload, a multiple of UPLOAD_CHUNK_ALIGNMENT
+    pub upload_chunk_size: u64,
+    // how to reconcile files that changed both locally and on Drive
+    pub conflict_policy: ConflictPolicy,
+    // extension each type of Google file is exported as, keyed by its MIME type; types
+    // without an entry are left out of the mount
+    pub export_formats: HashMap<String, String>,
+    pub docs_mode: export::GoogleDocsMode,
+    // MIME types of the Google files whose exports are writable, and converted back into
+    // the Google file when uploaded
+    pub reimport_types: HashSet<String>,
+    pub open_validation: OpenValidation,
+    // seconds the storage quota from Drive, and the free space on the local disk, are
+    // trusted before they're checked again
+    pub quota_ttl: u64,
+    // seconds the results of a search in `.search` are kept before it's run again
+    pub search_ttl: u64,
+    // folders at the root showing files from elsewhere on Drive, in the order they were
+    // turned on
+    pub views: Vec<View>,
+}
+
+/// When the local copy of a file is checked against Drive, as the file is opened.
+#[derive (Debug, Clone, Copy, PartialEq)]
+pub enum OpenValidation {
+    // trust the local copy, which is kept current by the folder listings
+    Never,
+    // check again once the last check is older than this many seconds
+    Ttl(u64),
+    Always,
+}
+
+impl OpenValidation {
+    pub fn from_str(s: &str) -> Option<OpenValidation> {
+        match s {
+            "never"  => Some(OpenValidation::Never),
+            "always" => Some(OpenValidation::Always),
+            _ if s.starts_with("ttl=") => s["ttl=".len()..].parse::<u64>().ok().map(OpenValidation::Ttl),
+            _ => None,
+        }
+    }
+}
+
+/// A read-only folder at the root of the mount with files from all over Drive, turned on
+/// with `view.shared=true`, `view.starred=true` or `view.recent=true`.
+#[derive (Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum View {
+    SharedWithMe,
+    Starred,
+    // ordered by when the files were last looked at, latest first
+    Recent,
+}
+
+impl View {
+    pub fn from_str(s: &str) -> Option<View> {
+        match s {
+            "shared"  => Some(View::SharedWithMe),
+            "starred" => Some(View::Starred),
+            "recent"  => Some(View::Recent),
+            _ => None,
+        }
+    }
+
+    /// The name of its folder at the root of the mount.
+    pub fn dir_name(&self) -> &'static str {
+        match *self {
+            View::SharedWithMe => "Shared with me",
+            View::Starred      => "Starred",
+            View::Recent       => "Recent",
+        }
+    }
+
+    /// The Drive query for the files in the view, and the order they're listed in.
+    pub fn query(&self) -> (&'static str, Option<&'static str>) {
+        match *self {
+            View::SharedWithMe => ("sharedWithMe", None),
+            View::Starred      => ("starred", None),
+            // files that were never opened have no viewedByMeTime
+            View::Recent       => ("viewedByMeTime > '1970-01-01T00:00:00'", Some("viewedByMeTime desc")),
+        }
+    }
+}
+
+// Drive rejects resumable upload chunks that aren't a multiple of 256 KiB
+pub const UPLOAD_CHUNK_ALIGNMENT: u64 = 256 * 1024;
+
+impl Default for MountConfig {
+    fn default() -> MountConfig {
+        MountConfig {
+            upload_delay: 5,
+            upload_max_backoff: 600,
+            upload_chunk_size: 32 * UPLOAD_CHUNK_ALIGNMENT,
+            conflict_policy: ConflictPolicy::KeepBoth,
+            export_formats: export::default_export_formats(),
+            docs_mode: export::GoogleDocsMode::Export,
+            reimport_types: HashSet::new(),
+            open_validation: OpenValidation::Ttl(60),
+            quota_ttl: 300,
+            search_ttl: 30,
+            views: Vec::new(),
+        }
+    }
+}
+
+impl MountConfig {
+    pub fn parse_options(&mut self, options: &str) -> Result<(), DriveError> {
+        for opt in options.split(',').filter(|opt| !opt.is_empty()) {
+            let mut kv = opt.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let value = kv.next();
+            try!(self.set_option(key, value));
+        }
+
+        Ok(())
+    }
+
+    fn set_option(&mut self, key: &str, value: Option<&str>) -> Result<(), DriveError> {
+        match key {
+            "upload_delay"       => self.upload_delay = try!(parse_u64_option(key, value)),
+            "upload_max_backoff" => self.upload_max_backoff = try!(parse_u64_option(key, value)),
+            "quota_ttl"          => self.quota_ttl = try!(parse_u64_option(key, value)),
+            "search_ttl"         => self.search_ttl = try!(parse_u64_option(key, value)),
+            "upload_chunk_size"  => {
+                let size = try!(parse_u64_option(key, value));
+                // round up to the next multiple of the chunk alignment
+                let chunks = (size + UPLOAD_CHUNK_ALIGNMENT - 1) / UPLOAD_CHUNK_ALIGNMENT;
+                self.upload_chunk_size = cmp::max(chunks, 1) * UPLOAD_CHUNK_ALIGNMENT;
+            },
+            "conflict" => {
+                self.conflict_policy = try!(value.and_then(ConflictPolicy::from_str)
+                                                 .ok_or(invalid_option(key, value)));
+            },
+            "docs" => {
+                self.docs_mode = try!(value.and_then(export::GoogleDocsMode::from_str)
+                                           .ok_or(invalid_option(key, value)));
+            },
+            // e.g. open_validation=ttl=30
+            "open_validation" => {
+                self.open_validation = try!(value.and_then(OpenValidation::from_str)
+                                                 .ok_or(invalid_option(key, value)));
+            },
+            // e.g. export.spreadsheet=csv, or export.drawing=none to leave drawings out
+            _ if key.starts_with("export.") => {
+                let google_type = format!("application/vnd.google-apps.{}", &key["export.".len()..]);
+                match value {
+                    Some("none") => {
+                        self.export_formats.remove(&google_type);
+                    },
+                    Some(ext) if export::export_mime_type(&google_type, ext).is_some() => {
+                        self.export_formats.insert(google_type, ext.to_string());
+                    },
+                    _ => return Err(invalid_option(key, value)),
+                }
+            },
+            // e.g. view.starred=true
+            _ if key.starts_with("view.") => {
+                let view = try!(View::from_str(&key["view.".len()..]).ok_or(invalid_option(key, value)));
+                match value {
+                    Some("true")  => if !self.views.contains(&view) { self.views.push(view) },
+                    Some("false") => self.views.retain(|enabled| enabled != &view),
+                    _ => return Err(invalid_option(key, value)),
+                }
+            },
+            // e.g. reimport.document=true
+            _ if key.starts_with("reimport.") => {
+                let google_type = format!("application/vnd.google-apps.{}", &key["reimport.".len()..]);
+                match value {
+                    Some("true")  => { self.reimport_types.insert(google_type); },
+                    Some("false") => { self.reimport_types.remove(&google_type); },
+                    _ => return Err(invalid_option(key, value)),
+                }
+            },
+            _ => return Err(invalid_option(key, value)),
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_u64_option(key: &str, value: Option<&str>) -> Result<u64, DriveError> {
+    value.and_then(|v| v.parse::<u64>().ok())
+         .ok_or(invalid_option(key, value))
+}
+
+fn invalid_option(key: &str, value: Option<&str>) -> DriveError {
+    DriveError {
+        kind: DriveErrorType::InvalidMountOption(format!("{}={}", key, value.unwrap_or(""))),
+        response: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use super::*;
+
+    fn parsed(options: &str) -> MountConfig {
+        let mut config = MountConfig::default();
+        config.parse_options(options).unwrap();
+        config
+    }
+
+    fn is_invalid(options: &str) -> bool {
+        match MountConfig::default().parse_options(options) {
+            Err(DriveError { kind: DriveErrorType::InvalidMountOption(_), .. }) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        let config = parsed("upload_delay=10,upload_max_backoff=600");
+        assert_eq!(config.upload_delay, 10);
+        assert_eq!(config.upload_max_backoff, 600);
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(is_invalid("upload_delay=soon"));
+        assert!(is_invalid("upload_delay"));
+        assert!(is_invalid("no_such_option=1"));
+    }
+
+    #[test]
+    fn empty_options_are_skipped() {
+        assert_eq!(parsed(",upload_delay=3,").upload_delay, 3);
+    }
+
+    #[test]
+    fn upload_chunk_size_is_aligned() {
+        assert_eq!(parsed("upload_chunk_size=1").upload_chunk_size, UPLOAD_CHUNK_ALIGNMENT);
+        assert_eq!(parsed("upload_chunk_size=0").upload_chunk_size, UPLOAD_CHUNK_ALIGNMENT);
+        assert_eq!(parsed(&format!("upload_chunk_size={}", 2 * UPLOAD_CHUNK_ALIGNMENT)).upload_chunk_size
+                  , 2 * UPLOAD_CHUNK_ALIGNMENT);
+        assert_eq!(parsed(&format!("upload_chunk_size={}", 2 * UPLOAD_CHUNK_ALIGNMENT + 1)).upload_chunk_size
+                  , 3 * UPLOAD_CHUNK_ALIGNMENT);
+    }
+
+    #[test]
+    fn export_formats() {
+        let config = parsed("export.spreadsheet=csv,export.drawing=none");
+        assert_eq!(config.export_formats.get("application/vnd.google-apps.spreadsheet").map(|ext| ext.as_str()), Some("csv"));
+        assert!(!config.export_formats.contains_key("application/vnd.google-apps.drawing"));
+        assert!(is_invalid("export.spreadsheet=mp3"));
+    }
+
+    #[test]
+    fn reimport_types() {
+        let config = parsed("reimport.document=true");
+        assert!(config.reimport_types.contains("application/vnd.google-apps.document"));
+        assert!(is_invalid("reimport.document=maybe"));
+    }
+
+    #[test]
+    fn open_validation() {
+        assert_eq!(parsed("open_validation=ttl=30").open_validation, OpenValidation::Ttl(30));
+        assert!(is_invalid("open_validation=sometimes"));
+    }
+
+    #[test]
+    fn quota_ttl() {
+        assert_eq!(parsed("quota_ttl=0").quota_ttl, 0);
+    }
+
+    #[test]
+    fn search_ttl() {
+        assert_eq!(parsed("search_ttl=5").search_ttl, 5);
+    }
+
+    #[test]
+    fn views_are_kept_in_order_once() {
+        let config = parsed("view.recent=true,view.shared=true,view.recent=true");
+        assert_eq!(config.views, vec![View::Recent, View::SharedWithMe]);
+    }
+
+    #[test]
+    fn views_can_be_turned_off() {
+        let config = parsed("view.starred=true,view.shared=true,view.starred=false");
+        assert_eq!(config.views, vec![View::SharedWithMe]);
+    }
+
+    #[test]
+    fn invalid_views() {
+        assert!(is_invalid("view.trash=true"));
+        assert!(is_invalid("view.starred=yes"));
+        assert!(is_invalid("view.starred"));
+    }
+}
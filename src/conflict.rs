@@ -0,0 +1,202 @@
+extern crate uuid;
+extern crate rusqlite;
+
+use std::ffi::CStr;
+use std::path::Path;
+
+use libc;
+use time;
+use uuid::Uuid;
+
+use types::*;
+
+/// What to do when a file was changed both locally and on Drive since it was last synced.
+#[derive (Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    // upload the local copy as a new `name (conflict from host date).ext` file, and keep
+    // the remote version under the original name
+    KeepBoth,
+    PreferLocal,
+    PreferRemote,
+}
+
+impl ConflictPolicy {
+    pub fn from_str(s: &str) -> Option<ConflictPolicy> {
+        match s {
+            "keep_both" => Some(ConflictPolicy::KeepBoth),
+            "local"     => Some(ConflictPolicy::PreferLocal),
+            "remote"    => Some(ConflictPolicy::PreferRemote),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ConflictPolicy::KeepBoth     => "keep_both",
+            ConflictPolicy::PreferLocal  => "local",
+            ConflictPolicy::PreferRemote => "remote",
+        }
+    }
+}
+
+pub const CREATE_CONFLICTS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS conflicts (
+    uuid           BLOB NOT NULL,
+    path           TEXT,
+    detected_at    INTEGER NOT NULL,
+    policy         TEXT NOT NULL,
+    base_version   TEXT,
+    remote_version TEXT,
+    conflict_name  TEXT
+)";
+
+#[derive (Debug, Clone)]
+pub struct ConflictRecord {
+    pub uuid: Uuid,
+    pub path: Option<String>,
+    pub detected_at: i64,
+    pub policy: String,
+    pub base_version: Option<String>,
+    pub remote_version: Option<String>,
+    // name of the copy made for the local version, under the keep_both policy
+    pub conflict_name: Option<String>,
+}
+
+pub fn log_conflict(conn: &rusqlite::Connection, conflict: &ConflictRecord) -> Result<(), DriveError> {
+    println!("conflict in {:?}: local copy based on version {:?}, Drive has version {:?}, resolving with policy {}"
+            , conflict.path, conflict.base_version, conflict.remote_version, conflict.policy);
+
+    try!(conn.execute("INSERT INTO conflicts (uuid, path, detected_at, policy, base_version, remote_version, conflict_name)
+                       VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        , &[ &conflict.uuid.clone().as_bytes().to_vec()
+           , &conflict.path
+           , &conflict.detected_at
+           , &conflict.policy
+           , &conflict.base_version
+           , &conflict.remote_version
+           , &conflict.conflict_name
+           ]
+    ));
+
+    Ok(())
+}
+
+pub fn recent_conflicts(conn: &rusqlite::Connection, limit: i64) -> Result<Vec<ConflictRecord>, DriveError> {
+    let mut stmt = try!(conn.prepare("SELECT uuid, path, detected_at, policy, base_version, remote_version, conflict_name
+                                      FROM conflicts
+                                      ORDER BY detected_at DESC
+                                      LIMIT :limit"));
+    let rows = try!(stmt.query_map_named(&[(":limit", &limit)]
+        , |row| -> ConflictRecord {
+            ConflictRecord {
+                uuid: Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).expect("failed to parse Uuid from conflict log"),
+                path: row.get(1),
+                detected_at: row.get(2),
+                policy: row.get(3),
+                base_version: row.get(4),
+                remote_version: row.get(5),
+                conflict_name: row.get(6),
+            }
+        }
+    ));
+
+    let mut conflicts = Vec::new();
+    for conflict in rows {
+        conflicts.push(try!(conflict));
+    }
+
+    Ok(conflicts)
+}
+
+/// Builds `name (conflict from host date).ext` for the local side of a conflict.
+pub fn conflict_file_name(name: &str) -> String {
+    let date = time::now().strftime("%Y-%m-%d %H.%M.%S")
+        .map(|tm| format!("{}", tm))
+        .unwrap_or(String::new());
+    let suffix = format!(" (conflict from {} {})", hostname(), date);
+
+    let path = Path::new(name);
+    match (path.file_stem().and_then(|stem| stem.to_str()), path.extension().and_then(|ext| ext.to_str())) {
+        (Some(stem), Some(ext)) => format!("{}{}.{}", stem, suffix, ext),
+        _ => format!("{}{}", name, suffix),
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    unsafe {
+        if libc::gethostname(buf.as_mut_ptr(), buf.len()) != 0 {
+            return "unknown host".to_string()
+        }
+        // gethostname doesn't promise a terminating nul on truncation
+        buf[buf.len() - 1] = 0;
+        CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use uuid::Uuid;
+    use super::*;
+
+    #[test]
+    fn policies() {
+        for policy in &[ConflictPolicy::KeepBoth, ConflictPolicy::PreferLocal, ConflictPolicy::PreferRemote] {
+            assert_eq!(ConflictPolicy::from_str(policy.as_str()), Some(*policy));
+        }
+        assert_eq!(ConflictPolicy::from_str("newest"), None);
+        assert_eq!(ConflictPolicy::from_str(""), None);
+    }
+
+    #[test]
+    fn conflict_name_keeps_the_extension() {
+        let name = conflict_file_name("report.txt");
+        assert!(name.starts_with(&format!("report (conflict from {} ", hostname())));
+        assert!(name.ends_with(").txt"));
+
+        // only the last extension counts
+        let name = conflict_file_name("backup.tar.gz");
+        assert!(name.starts_with("backup.tar (conflict from "));
+        assert!(name.ends_with(").gz"));
+    }
+
+    #[test]
+    fn conflict_name_without_extension() {
+        let name = conflict_file_name("Makefile");
+        assert!(name.starts_with(&format!("Makefile (conflict from {} ", hostname())));
+        assert!(name.ends_with(")"));
+
+        assert!(conflict_file_name(".bashrc").starts_with(".bashrc (conflict from "));
+    }
+
+    #[test]
+    fn logged_conflicts_are_listed_newest_first() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(CREATE_CONFLICTS_TABLE, &[]).unwrap();
+
+        let uuid = Uuid::new_v4();
+        for detected_at in 1..4 {
+            log_conflict(&conn, &ConflictRecord {
+                uuid: uuid,
+                path: Some("/tmp/report.txt".to_string()),
+                detected_at: detected_at,
+                policy: ConflictPolicy::KeepBoth.as_str().to_string(),
+                base_version: Some("4".to_string()),
+                remote_version: Some("6".to_string()),
+                conflict_name: if detected_at == 3 { Some("report (conflict).txt".to_string()) } else { None },
+            }).unwrap();
+        }
+
+        let conflicts = recent_conflicts(&conn, 2).unwrap();
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0].detected_at, 3);
+        assert_eq!(conflicts[1].detected_at, 2);
+        assert_eq!(conflicts[0].uuid, uuid);
+        assert_eq!(conflicts[0].path, Some("/tmp/report.txt".to_string()));
+        assert_eq!(conflicts[0].policy, "keep_both");
+        assert_eq!(conflicts[0].base_version, Some("4".to_string()));
+        assert_eq!(conflicts[0].remote_version, Some("6".to_string()));
+        assert_eq!(conflicts[0].conflict_name, Some("report (conflict).txt".to_string()));
+        assert_eq!(conflicts[1].conflict_name, None);
+    }
+}
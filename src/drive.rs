@@ -9,16 +9,23 @@ use time;
 use time::{Timespec, Tm};
 
 use std::str;
+use std::cmp;
 
 use hyper::{Client};
-use hyper::header::{ContentType, Authorization, Bearer};
-use hyper::client::{Body};
+use hyper::header::{ContentType, Authorization, Bearer, Location, ContentRange, ContentRangeSpec};
+use hyper::client::{Body, Response};
+use hyper::method::Method;
 use mime::{Mime, TopLevel, SubLevel};
 
 use std::collections::hash_map::HashMap;
+use std::collections::BTreeMap;
 use rustc_serialize::json::{Json, ToJson, Decoder, as_pretty_json};
 use rustc_serialize::{json, Decodable};
 use std::io;
+use std::mem;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use libc;
 use std::io::SeekFrom;
 use std::io::prelude::*;
 use std::fs::{File, DirBuilder,OpenOptions};
@@ -32,6 +39,13 @@ use uuid::Uuid;
 
 use types::*;
 use filetree::*;
+use config::{MountConfig, OpenValidation};
+use upload;
+use upload::PendingUpload;
+use conflict::*;
+use export::*;
+use posix;
+use xattr::Xattr;
 
 pub struct DriveFileDownloader {
     pub client: Client,
@@ -39,6 +53,20 @@ pub struct DriveFileDownloader {
 
     uuid_map: HashMap<Uuid, DriveFileResponse>,
     conn: rusqlite::Connection,
+    config: MountConfig,
+    // where the local copies are kept
+    root_path: PathBuf,
+    // the last quota fetched from Drive, and when it was fetched
+    quota: Option<(StorageQuota, i64)>,
+    // when fetching the quota last failed, so an offline mount doesn't keep trying
+    quota_failed_at: i64,
+    // the room left on Drive and the local disk, and when it was worked out. Kept as long
+    // as the quota, as every growing write checks it
+    space: Option<(StorageSpace, i64)>,
+    // set while Drive can't be reached for the quota, so that's only logged once
+    quota_offline: bool,
+    // recent search results, and when each search was run
+    searches: HashMap<String, (Vec<SearchResult>, i64)>,
 }
 
 const CACHE_FILE: &'static str = "access";
@@ -51,8 +79,123 @@ struct DownloadedFileInformation {
     path: PathBuf,
 }
 
+enum UploadProgress {
+    // Drive has stored everything before this offset
+    Received(u64),
+    // holds the file resource Drive responded with
+    Complete(String),
+}
+
+// symlinks are kept on Drive as small files, with their target in this app property
+const SYMLINK_APP_PROPERTY: &'static str = "driver_symlink";
+
+// past versions of files are downloaded here, named by revision ID. Revisions never
+// change, so once downloaded they're kept
+const REVISION_CACHE_DIR: &'static str = ".drive_revisions";
+
+// files read through the views that aren't in the mount are downloaded here, named by ID
+const REMOTE_CACHE_DIR: &'static str = ".drive_views";
+
+// what we ask Drive to send back when an upload finishes
+const UPLOADED_FILE_FIELDS: &'static str = "id%2Cversion%2CheadRevisionId%2Cmd5Checksum%2Csize";
+
+header! { (XUploadContentLength, "X-Upload-Content-Length") => [u64] }
+header! { (XUploadContentType, "X-Upload-Content-Type") => [String] }
+
+fn upload_progress(mut resp: Response) -> Result<UploadProgress, DriveError> {
+    match resp.status.to_u16() {
+        200 | 201 => {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            Ok(UploadProgress::Complete(resp_string))
+        },
+        308 => {
+            // Drive reports the stored bytes as "Range: bytes=0-<last byte>", and leaves the
+            // header out when it has none of them
+            let received = resp.headers.get_raw("Range")
+                .and_then(|raw| raw.first())
+                .and_then(|range| str::from_utf8(range).ok())
+                .and_then(|range| range.rsplit('-').next())
+                .and_then(|last| last.trim().parse::<u64>().ok())
+                .map(|last| last + 1)
+                .unwrap_or(0);
+            Ok(UploadProgress::Received(received))
+        },
+        status => {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            Err(DriveError {
+                kind: if status == 404 || status == 410 {
+                    DriveErrorType::UploadSessionExpired
+                } else {
+                    upload_error_type(&resp_string)
+                },
+                response: Some(resp_string),
+            })
+        }
+    }
+}
+
+// Drive turns down uploads that would go over the quota with a 403, which only the
+// reason in the body tells apart from other refusals
+fn upload_error_type(resp_string: &str) -> DriveErrorType {
+    if resp_string.contains("storageQuotaExceeded") || resp_string.contains("quotaExceeded") {
+        DriveErrorType::QuotaExceeded
+    } else {
+        DriveErrorType::UploadFailed
+    }
+}
+
+// the checksum and size Drive had for a file, as long as they belong to `version`, the
+// latest version of it we've heard of, or a later one. Without one the cache is taken as
+// it is
+fn cached_remote_checksum(conn: &rusqlite::Connection, uuid: &Uuid, version: Option<&str>) -> Result<Option<FileCheckResponse>, rusqlite::Error> {
+    let (checksum, size, cached_version) = try!(conn.query_row_named(
+        "SELECT remote_checksum, remote_size, remote_version FROM files WHERE uuid=:uuid"
+        , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+        , |row| -> (Option<String>, Option<i64>, Option<String>) {
+            (row.get(0), row.get(1), row.get(2))
+        }
+    ));
+    // versions only ever go up, and ours may lag behind the cache after an upload
+    let current = match (version, cached_version.as_ref()) {
+        (None, _) => true,
+        (Some(version), Some(cached)) => match (version.parse::<i64>(), cached.parse::<i64>()) {
+            (Ok(version), Ok(cached)) => cached >= version,
+            _ => cached.as_str() == version,
+        },
+        (Some(_), None) => false,
+    };
+    if !current {
+        return Ok(None)
+    }
+
+    Ok(match (checksum, size) {
+        (Some(checksum), Some(size)) => Some(FileCheckResponse {
+            md5Checksum: checksum,
+            size: size as u64,
+            version: cached_version,
+        }),
+        _ => None,
+    })
+}
+
+// replaces the cached checksum and size of a file with the ones in a listing, or any other
+// response from Drive, when it's of a different version than the cached ones
+fn replace_remote_checksum(conn: &rusqlite::Connection, uuid: &Uuid, checksum: &Option<String>, size: Option<u64>, version: &Option<String>) -> Result<i32, rusqlite::Error> {
+    conn.execute("UPDATE files
+                  SET remote_checksum=$1, remote_size=$2, remote_version=$3
+                  WHERE uuid=$4 AND remote_version IS NOT $3",
+        &[ checksum,
+           &size.map(|size| size as i64),
+           version,
+           &uuid.clone().as_bytes().to_vec(),
+         ]
+    )
+}
+
 impl DriveFileDownloader {
-    pub fn new(root_uuid: Uuid, root_id: String, file_path: PathBuf, db_conn: rusqlite::Connection) -> Result<DriveFileDownloader, DriveError> {
+    pub fn new(root_uuid: Uuid, root_id: String, file_path: PathBuf, db_conn: rusqlite::Connection, config: MountConfig) -> Result<DriveFileDownloader, DriveError> {
         let c = Client::new();
 
         let mut handle = try!(OpenOptions::new()
@@ -84,15 +227,53 @@ impl DriveFileDownloader {
                 0
             }
         );
+        add_column(&db_conn, "files", "version TEXT");
+        add_column(&db_conn, "files", "head_revision TEXT");
+        add_column(&db_conn, "files", "target_id TEXT");
+        add_column(&db_conn, "files", "parent_id TEXT");
+        add_column(&db_conn, "files", "link_path TEXT");
+        add_column(&db_conn, "files", "posix_mode INTEGER");
+        add_column(&db_conn, "files", "posix_uid INTEGER");
+        add_column(&db_conn, "files", "posix_gid INTEGER");
+        add_column(&db_conn, "files", "posix_mtime TEXT");
+        // checksum and size Drive has for a file, and the version they belong to
+        add_column(&db_conn, "files", "remote_checksum TEXT");
+        add_column(&db_conn, "files", "remote_size INTEGER");
+        add_column(&db_conn, "files", "remote_version TEXT");
+        // when the local copy was last checked against Drive, as it was opened
+        add_column(&db_conn, "files", "validated_at INTEGER");
+        try!(db_conn.execute(upload::CREATE_UPLOADS_TABLE, &[]));
+        // for queues created before uploads could be resumed, or be of metadata only
+        add_column(&db_conn, "uploads", "session_uri TEXT");
+        add_column(&db_conn, "uploads", "metadata_only INTEGER NOT NULL DEFAULT 0");
+        add_column(&db_conn, "uploads", "error_code TEXT");
+        try!(db_conn.execute(CREATE_CONFLICTS_TABLE, &[]));
 
         let mut uuid_map = HashMap::new();
         uuid_map.insert(root_uuid, DriveFileResponse {
             kind: "drive#file".to_string(),
             id: root_id.clone(),
             name: "".to_string(),
-            mimeType: "application/vnd.google-apps.folder".to_string(),
+            mimeType: FOLDER_MIME_TYPE.to_string(),
             parents: vec!(root_id),
-            path: Some(file_path),
+            path: Some(file_path.clone()),
+            size: None,
+            md5Checksum: None,
+            version: None,
+            headRevisionId: None,
+            trashed: None,
+            owners: None,
+            capabilities: None,
+            webViewLink: None,
+            exportLinks: None,
+            shortcutDetails: None,
+            appProperties: None,
+            properties: None,
+            description: None,
+            starred: None,
+            modifiedTime: None,
+            createdTime: None,
+            viewedByMeTime: None,
         });
 
         Ok(DriveFileDownloader {
@@ -105,9 +286,952 @@ impl DriveFileDownloader {
             },
             uuid_map: uuid_map,
             conn: db_conn,
+            config: config,
+            root_path: file_path,
+            quota: None,
+            quota_failed_at: 0,
+            space: None,
+            quota_offline: false,
+            searches: HashMap::new(),
         })
     }
 
+    /// Replaces the file's data on Drive with the local contents of the file. The file is
+    /// streamed from disk through a resumable upload session, `upload_chunk_size` bytes at
+    /// a time. The session is kept in the upload queue, so an interrupted upload picks up
+    /// where Drive left off on the next attempt instead of starting over.
+    pub fn upload_file(&self, upload: &PendingUpload) -> Result<(), DriveError> {
+        let uuid = &upload.uuid;
+        let (fid, path, mime_type) = try!(self.conn.query_row_named("SELECT id, path, mimetype FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> (String, String, String) {
+                (row.get(0),
+                 row.get(1),
+                 row.get::<i32, Option<String>>(2).unwrap_or(String::new())
+                )
+            }
+        ));
+
+        let import_type = self.import_type(&mime_type);
+        if is_google_apps_type(&mime_type) && import_type.is_none() {
+            println!("not uploading {}, it's a read-only copy of a Google file", path);
+            return Ok(())
+        }
+
+        if upload.metadata_only && !fid.is_empty() {
+            return self.update_metadata(uuid, &fid)
+        }
+
+        let session_uri = match upload.session_uri {
+            Some(ref uri) => uri.clone(),
+            None if fid.is_empty() => {
+                // the file was made locally, and has yet to be created on Drive
+                let total = try!(try!(File::open(&path)).metadata()).len();
+                let uri = try!(self.start_upload_session(Method::Post
+                    , &format!("https://www.googleapis.com/upload/drive/v3/files\
+                                ?uploadType=resumable\
+                                &fields={}", UPLOADED_FILE_FIELDS)
+                    , &try!(self.new_file_metadata(uuid, &path))
+                    , None
+                    , total));
+                try!(self.conn.execute("UPDATE uploads
+                                        SET session_uri=$1
+                                        WHERE uuid=$2 AND queued_at=$3"
+                    , &[ &uri
+                       , &uuid.clone().as_bytes().to_vec()
+                       , &upload.queued_at
+                       ]
+                ));
+                uri
+            },
+            None => {
+                // an upload that already has a session was checked when it was started
+                if !try!(self.resolve_upload_conflict(uuid, &fid, &mime_type, &path)) {
+                    return Ok(())
+                }
+
+                // a re-imported export is converted back to the Google file it came from
+                let mut metadata = BTreeMap::new();
+                if import_type.is_some() {
+                    metadata.insert("mimeType".to_string(), mime_type.to_json());
+                }
+                let app_properties = try!(self.posix_app_properties(uuid));
+                if !app_properties.is_empty() {
+                    metadata.insert("appProperties".to_string(), Json::Object(app_properties));
+                }
+
+                let total = try!(try!(File::open(&path)).metadata()).len();
+                let uri = try!(self.start_upload_session(Method::Patch
+                    , &format!("https://www.googleapis.com/upload/drive/v3/files/{}\
+                                ?uploadType=resumable\
+                                &fields={}", fid, UPLOADED_FILE_FIELDS)
+                    , &Json::Object(metadata).to_string()
+                    , import_type
+                    , total));
+                try!(self.conn.execute("UPDATE uploads
+                                        SET session_uri=$1
+                                        WHERE uuid=$2 AND queued_at=$3"
+                    , &[ &uri
+                       , &uuid.clone().as_bytes().to_vec()
+                       , &upload.queued_at
+                       ]
+                ));
+                uri
+            }
+        };
+
+        let uploaded = match self.send_upload(&session_uri, &path) {
+            Err(DriveError { kind: DriveErrorType::UploadSessionExpired, response }) => {
+                // a fresh session will be started on the next attempt
+                try!(self.conn.execute("UPDATE uploads
+                                        SET session_uri=NULL
+                                        WHERE uuid=$1 AND queued_at=$2"
+                    , &[ &uuid.clone().as_bytes().to_vec()
+                       , &upload.queued_at
+                       ]
+                ));
+                return Err(DriveError {
+                    kind: DriveErrorType::UploadSessionExpired,
+                    response: response,
+                })
+            },
+            result => try!(result),
+        };
+
+        // Drive has no checksum or size for a re-imported Google file, so we keep those of
+        // the local export
+        let checksum = match uploaded.md5Checksum {
+            Some(checksum) => checksum,
+            None => try!(get_file_checksum(Path::new(&path))),
+        };
+        let size = match uploaded.size {
+            Some(size) => size,
+            None => try!(std::fs::metadata(&path)).len(),
+        };
+
+        try!(self.conn.execute("UPDATE files
+                                SET id=$1, checksum=$2, size=$3, version=$4, head_revision=$5,
+                                    remote_checksum=$6, remote_size=$7, remote_version=$4
+                                WHERE uuid=$8",
+            &[ &uploaded.id,
+               &checksum,
+               &(size as i64),
+               &uploaded.version,
+               &uploaded.headRevisionId,
+               &uploaded.md5Checksum,
+               &uploaded.size.map(|size| size as i64),
+               &uuid.clone().as_bytes().to_vec(),
+             ]
+        ));
+
+        Ok(())
+    }
+
+    // metadata for a file made locally, when it's created on Drive
+    fn new_file_metadata(&self, uuid: &Uuid, path: &str) -> Result<String, DriveError> {
+        let (parent_id, link_path) = try!(self.conn.query_row_named("SELECT parent_id, link_path FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> (Option<String>, Option<String>) {
+                (row.get(0), row.get(1))
+            }
+        ));
+        // the parent may itself still be waiting to be created
+        let parent_id = try!(parent_id.and_then(|id| if id.is_empty() { None } else { Some(id) })
+            .ok_or(DriveError {
+                kind: DriveErrorType::NoPathForParent,
+                response: None,
+            }));
+        let name = try!(Path::new(path).file_name().and_then(|name| name.to_str()).ok_or(DriveError {
+            kind: DriveErrorType::NoFileName,
+            response: None,
+        }));
+
+        let mut metadata = BTreeMap::new();
+        let mut app_properties = try!(self.posix_app_properties(uuid));
+        metadata.insert("name".to_string(), name.to_json());
+        metadata.insert("parents".to_string(), vec!(parent_id).to_json());
+        if let Some(link_path) = link_path {
+            app_properties.insert(SYMLINK_APP_PROPERTY.to_string(), link_path.to_json());
+            metadata.insert("mimeType".to_string(), "text/plain".to_json());
+        }
+        if !app_properties.is_empty() {
+            metadata.insert("appProperties".to_string(), Json::Object(app_properties));
+        }
+
+        Ok(Json::Object(metadata).to_string())
+    }
+
+    // the POSIX metadata set on a file through the mount, as app properties
+    fn posix_app_properties(&self, uuid: &Uuid) -> Result<BTreeMap<String, Json>, DriveError> {
+        let attrs = try!(self.conn.query_row_named("SELECT posix_mode, posix_uid, posix_gid, posix_mtime FROM files
+                                                    WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> PosixAttrs {
+                PosixAttrs {
+                    mode: row.get::<i32, Option<i64>>(0).map(|mode| mode as u16),
+                    uid: row.get::<i32, Option<i64>>(1).map(|uid| uid as u32),
+                    gid: row.get::<i32, Option<i64>>(2).map(|gid| gid as u32),
+                    mtime: row.get::<i32, Option<String>>(3).and_then(|mtime| posix::parse_timespec(&mtime)),
+                }
+            }
+        ));
+
+        Ok(posix::to_app_properties(&attrs))
+    }
+
+    // sends only the file's POSIX metadata, leaving its contents alone
+    fn update_metadata(&self, uuid: &Uuid, fid: &str) -> Result<(), DriveError> {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("appProperties".to_string(), Json::Object(try!(self.posix_app_properties(uuid))));
+
+        // the stored version is left alone, as it's there to tell whether the contents
+        // changed under us
+        try!(self.patch_metadata(fid, metadata));
+        Ok(())
+    }
+
+    // changes a file's metadata on Drive, returning the file as it is afterwards
+    fn patch_metadata(&self, fid: &str, metadata: BTreeMap<String, Json>) -> Result<DriveFileResponse, DriveError> {
+        let mut resp = try!(self.client
+            .patch(&format!("https://www.googleapis.com/drive/v3/files/{}?fields={}"
+                           , fid, DriveFileResponse::fields()))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
+            .body(&Json::Object(metadata).to_string())
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::UploadFailed,
+                response: Some(resp_string),
+            })
+        }
+
+        json::decode(&resp_string).map_err(From::from)
+    }
+
+    /// The Drive ID of a file, which a file made locally only has once it's been uploaded.
+    pub fn drive_id(&self, uuid: &Uuid) -> Result<String, DriveError> {
+        let fid = try!(self.conn.query_row_named("SELECT id FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> String { row.get(0) }
+        ));
+        if fid.is_empty() {
+            return Err(DriveError {
+                kind: DriveErrorType::NotYetUploaded,
+                response: None,
+            })
+        }
+
+        Ok(fid)
+    }
+
+    // the file's metadata from the last listing, or from Drive for files that weren't in one
+    fn file_metadata(&self, uuid: &Uuid) -> Result<DriveFileResponse, DriveError> {
+        match self.uuid_map.get(uuid) {
+            Some(fr) => Ok(fr.clone()),
+            None => self.get_remote_file(&try!(self.drive_id(uuid))),
+        }
+    }
+
+    pub fn list_permissions(&self, fid: &str) -> Result<Vec<PermissionResponse>, DriveError> {
+        let mut resp = try!(self.client
+            .get(&format!("https://www.googleapis.com/drive/v3/files/{}/permissions\
+                          ?fields=permissions(id%2Ctype%2Crole%2CemailAddress%2Cdomain%2CdisplayName)", fid))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        let json = try!(Json::from_str(&resp_string));
+        let permissions = try!(json.find("permissions").and_then(|list| list.as_array()).ok_or(DriveError {
+            kind: DriveErrorType::JsonInvalidAttribute,
+            response: Some(resp_string.clone()),
+        }));
+        Ok(permissions.iter().filter_map(PermissionResponse::from_json).collect())
+    }
+
+    /// Shares a file with `with`, which is an email address, a domain, or `anyone` for
+    /// everyone with the link.
+    pub fn share(&self, fid: &str, with: &str, role: &str) -> Result<PermissionResponse, DriveError> {
+        let mut permission = BTreeMap::new();
+        permission.insert("role".to_string(), role.to_json());
+        if with == "anyone" {
+            permission.insert("type".to_string(), "anyone".to_json());
+        } else if with.contains('@') {
+            permission.insert("type".to_string(), "user".to_json());
+            permission.insert("emailAddress".to_string(), with.to_json());
+        } else {
+            permission.insert("type".to_string(), "domain".to_json());
+            permission.insert("domain".to_string(), with.to_json());
+        }
+
+        let mut resp = try!(self.client
+            .post(&format!("https://www.googleapis.com/drive/v3/files/{}/permissions\
+                           ?fields=id%2Ctype%2Crole%2CemailAddress%2Cdomain%2CdisplayName", fid))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
+            .body(&Json::Object(permission).to_string())
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::UploadFailed,
+                response: Some(resp_string),
+            })
+        }
+
+        let json = try!(Json::from_str(&resp_string));
+        PermissionResponse::from_json(&json).ok_or(DriveError {
+            kind: DriveErrorType::JsonInvalidAttribute,
+            response: Some(resp_string.clone()),
+        })
+    }
+
+    /// Takes away every permission given to `with`, as `share` understands it. Returns how
+    /// many there were.
+    pub fn unshare(&self, fid: &str, with: &str) -> Result<usize, DriveError> {
+        let permissions = try!(self.list_permissions(fid));
+        let matching = permissions.iter().filter(|permission| {
+            permission.role != "owner" && (
+                (with == "anyone" && permission.kind == "anyone")
+                || permission.emailAddress.as_ref().map(|email| email == with).unwrap_or(false)
+                || permission.domain.as_ref().map(|domain| domain == with).unwrap_or(false))
+        });
+
+        let mut removed = 0;
+        for permission in matching {
+            let mut resp = try!(self.client
+                .delete(&format!("https://www.googleapis.com/drive/v3/files/{}/permissions/{}", fid, permission.id))
+                .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+                .send());
+            if !resp.status.is_success() {
+                let mut resp_string = String::new();
+                try!(resp.read_to_string(&mut resp_string));
+                return Err(DriveError {
+                    kind: DriveErrorType::UploadFailed,
+                    response: Some(resp_string),
+                })
+            }
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    // records a file from a listing of the folder `parent_id` in the drive db, and returns
+    // it as it's added to the tree. None for files that are left out of the mount
+    fn listed_file(&mut self, fr: DriveFileResponse, parent_id: &str, parent_path: &Path, root_folder_uuid: &Uuid) -> Option<FileResponse> {
+        let kind = if fr.mimeType == FOLDER_MIME_TYPE {
+            FileType::Directory
+        } else if fr.mimeType == SHORTCUT_MIME_TYPE || symlink_target(&fr).is_some() {
+            FileType::Symlink
+        } else {
+            FileType::RegularFile
+        };
+
+        let name = match self.local_name(&fr) {
+            Some(name) => name,
+            None => {
+                println!("no export format for {} ({}), leaving it out", fr.name, fr.mimeType);
+                return None
+            }
+        };
+        let mut posix = posix::from_app_properties(fr.appProperties.as_ref());
+        // exports are only copies of the real document, so changes to them would be lost,
+        // unless they're converted back on upload
+        if kind != FileType::Symlink
+        && is_google_apps_type(&fr.mimeType) && self.import_type(&fr.mimeType).is_none() {
+            posix.mode = Some(0o444);
+        }
+        // nor would changes to a file shared with us read-only ever be accepted
+        let can_edit = fr.capabilities.as_ref().and_then(|caps| caps.canEdit).unwrap_or(true);
+        if !can_edit && kind == FileType::RegularFile {
+            posix.mode = Some(posix.mode.unwrap_or(0o777) & !0o222);
+        }
+
+        let times = FileTimes {
+            modified: fr.modifiedTime.as_ref().and_then(|t| parse_rfc3339(t)),
+            created: fr.createdTime.as_ref().and_then(|t| parse_rfc3339(t)),
+            viewed: fr.viewedByMeTime.as_ref().and_then(|t| parse_rfc3339(t)),
+        };
+
+        let mut path = parent_path.to_owned();
+        path.push(name.clone());
+        let target_id = fr.shortcutDetails.as_ref().map(|details| details.targetId.clone());
+        let link_path = symlink_target(&fr);
+
+        // a file in several folders is only kept once, under the folder the filetree puts
+        // it in, so its path is left to `retreive_file`
+        let uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE id=:id"
+            , &[(":id", &fr.id)]
+            , |row| -> Uuid {
+                Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).expect("failed to parse Uuid from drive db storage")
+            }
+        ).and_then(|uuid| -> Result<Uuid, rusqlite::Error> {
+            self.conn.execute("UPDATE files
+                               SET mimetype=$1, target_id=$2, link_path=$3
+                               WHERE uuid=$4"
+                , &[ &fr.mimeType
+                   , &target_id
+                   , &link_path
+                   , &uuid.clone().as_bytes().to_vec()
+                   ]
+            ).and_then(|_| {
+                replace_remote_checksum(&self.conn, &uuid, &fr.md5Checksum, fr.size, &fr.version)
+            }).unwrap_or_else(|err| {
+                println!("couldn't update file in drive db, err: {:?}", err);
+                0
+            });
+
+            Ok(uuid)
+        }).unwrap_or_else(|_| {
+            let uuid = Uuid::new_v4();
+            self.conn.execute("INSERT INTO files (uuid, id, mimetype, path, parent_id, target_id, link_path,
+                                                  remote_checksum, remote_size, remote_version)
+                               VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)"
+                , &[ &uuid.clone().as_bytes().to_vec()
+                   , &fr.id
+                   , &fr.mimeType
+                   , &(path.to_str().expect("fadsfnjfsad"))
+                   , &parent_id
+                   , &target_id
+                   , &link_path
+                   , &fr.md5Checksum
+                   , &fr.size.map(|size| size as i64)
+                   , &fr.version
+                   ]
+            ).unwrap_or_else(|_| {
+                println!("file already in drive db: {}", fr.name);
+                0
+            });
+
+            uuid
+        });
+
+        {
+            self.uuid_map.insert(uuid, fr.clone());
+        }
+
+        // a file with several parents turns up in the listing of each of them, and is
+        // reported as a child of whichever folder is being listed
+        let parent_uuid = root_folder_uuid.clone();
+
+//        println!("drive adding path: {:?} {:?}", uuid.clone().as_bytes().to_vec(), path);
+        Some(FileResponse {
+            uuid: uuid,
+            parent_uuid: parent_uuid,
+            kind: kind,
+            name: name,
+            posix: posix,
+            times: times,
+            source_data: SourceData::Drive(fr)
+        })
+    }
+
+    /// Deletes everything in the trash for good.
+    pub fn empty_trash(&self) -> Result<(), DriveError> {
+        let mut resp = try!(self.client
+            .delete("https://www.googleapis.com/drive/v3/files/trash")
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+        if !resp.status.is_success() {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            return Err(DriveError {
+                kind: DriveErrorType::UploadFailed,
+                response: Some(resp_string),
+            })
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the local copy of a file with one of its past versions, and queues it to
+    /// be uploaded as the newest.
+    pub fn restore_revision(&self, uuid: &Uuid, revision_id: &str) -> Result<u64, DriveError> {
+        let revision_path = try!(self.retreive_revision(uuid, revision_id));
+        let size = try!(std::fs::copy(&revision_path, try!(self.local_path(uuid))));
+        try!(self.mark_dirty(uuid));
+        Ok(size)
+    }
+
+    fn set_xattr_value(&mut self, uuid: &Uuid, name: &str, value: Option<&str>) -> Result<(), DriveError> {
+        let xattr = try!(Xattr::from_name(name).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchXattr,
+            response: None,
+        }));
+        if !xattr.is_writable() {
+            return Err(DriveError {
+                kind: DriveErrorType::ReadOnlyXattr,
+                response: None,
+            })
+        }
+
+        let fid = try!(self.drive_id(uuid));
+        let fr = try!(self.patch_metadata(&fid, try!(xattr.patch(value))));
+        if let Some(cached) = self.uuid_map.get_mut(uuid) {
+            let cached_path = cached.path.clone();
+            *cached = fr;
+            cached.path = cached_path;
+        }
+
+        Ok(())
+    }
+
+    fn resolve_upload_conflict(&self, uuid: &Uuid, fid: &str, mime_type: &str, path: &str) -> Result<bool, DriveError> {
+        let remote = try!(self.get_remote_version(fid));
+        self.resolve_conflict(uuid, fid, mime_type, path, &remote)
+    }
+
+    /// Compares the remote version of a file with the one our local copy is based on, and
+    /// settles any difference according to the conflict policy. Returns whether the local
+    /// copy should still replace the remote file.
+    fn resolve_conflict(&self, uuid: &Uuid, fid: &str, mime_type: &str, path: &str, remote: &RemoteVersionResponse) -> Result<bool, DriveError> {
+        let (base_version, base_revision) = try!(self.conn.query_row_named("SELECT version, head_revision FROM files
+                                                                            WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> (Option<String>, Option<String>) {
+                (row.get(0), row.get(1))
+            }
+        ));
+
+        // the head revision only moves when the content changes, while the version is bumped
+        // by any change at all, so the revision is the better test when Drive has one
+        let conflicted = match (&base_revision, &remote.headRevisionId) {
+            (&Some(ref base), &Some(ref remote)) => base != remote,
+            _ => match (&base_version, &remote.version) {
+                (&Some(ref base), &Some(ref remote)) => base != remote,
+                // we don't know what the local copy was based on
+                _ => false,
+            }
+        };
+        if !conflicted {
+            return Ok(true)
+        }
+
+        let policy = self.config.conflict_policy;
+        let conflict_name = if policy == ConflictPolicy::KeepBoth {
+            Some(conflict_file_name(&remote.name))
+        } else {
+            None
+        };
+        try!(log_conflict(&self.conn, &ConflictRecord {
+            uuid: uuid.clone(),
+            path: Some(path.to_string()),
+            detected_at: time::get_time().sec,
+            policy: policy.as_str().to_string(),
+            base_version: base_version,
+            remote_version: remote.version.clone(),
+            conflict_name: conflict_name.clone(),
+        }));
+
+        match policy {
+            ConflictPolicy::PreferLocal => {
+                // from here on the local copy counts as an edit of the remote version, so the
+                // same conflict isn't raised again
+                try!(self.conn.execute("UPDATE files
+                                        SET version=$1, head_revision=$2
+                                        WHERE uuid=$3",
+                    &[ &remote.version,
+                       &remote.headRevisionId,
+                       &uuid.clone().as_bytes().to_vec(),
+                     ]
+                ));
+                Ok(true)
+            },
+            ConflictPolicy::PreferRemote => {
+                try!(self.download_remote_content(uuid, fid, mime_type, path, remote));
+                Ok(false)
+            },
+            ConflictPolicy::KeepBoth => {
+                // the local copy goes up as a new file next to the original, which then
+                // takes the remote contents
+                let mut metadata = BTreeMap::new();
+                metadata.insert("name".to_string(), conflict_name.to_json());
+                metadata.insert("parents".to_string(), remote.parents.to_json());
+                let import_type = self.import_type(mime_type);
+                if import_type.is_some() {
+                    metadata.insert("mimeType".to_string(), mime_type.to_json());
+                }
+
+                let total = try!(try!(File::open(path)).metadata()).len();
+                let session_uri = try!(self.start_upload_session(Method::Post
+                    , &format!("https://www.googleapis.com/upload/drive/v3/files\
+                                ?uploadType=resumable\
+                                &fields={}", UPLOADED_FILE_FIELDS)
+                    , &Json::Object(metadata).to_string()
+                    , import_type
+                    , total));
+                try!(self.send_upload(&session_uri, path));
+
+                try!(self.download_remote_content(uuid, fid, mime_type, path, remote));
+                Ok(false)
+            },
+        }
+    }
+
+    fn get_remote_version(&self, fid: &str) -> Result<RemoteVersionResponse, DriveError> {
+        let mut resp = try!(self.client
+            .get(&format!("https://www.googleapis.com/drive/v3/files/{}\
+                          ?fields=name%2Cparents%2Cversion%2CheadRevisionId", fid))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        json::decode(&resp_string).map_err(From::from)
+    }
+
+    fn get_storage_quota(&mut self) -> Result<StorageQuota, DriveError> {
+        let now = time::get_time().sec;
+        if let Some((ref quota, fetched_at)) = self.quota {
+            if now - fetched_at < self.config.quota_ttl as i64 {
+                return Ok(quota.clone())
+            }
+        }
+        // after a failure Drive is left alone for as long as a quota would have been kept
+        if now - self.quota_failed_at < self.config.quota_ttl as i64 {
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: None,
+            })
+        }
+
+        match self.fetch_storage_quota() {
+            Ok(quota) => {
+                self.quota = Some((quota.clone(), now));
+                Ok(quota)
+            },
+            Err(err) => {
+                self.quota_failed_at = now;
+                Err(err)
+            }
+        }
+    }
+
+    fn fetch_storage_quota(&self) -> Result<StorageQuota, DriveError> {
+        let mut resp = try!(self.client
+            .get("https://www.googleapis.com/drive/v3/about?fields=storageQuota")
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        let about: AboutResponse = try!(json::decode(&resp_string));
+        Ok(about.storageQuota)
+    }
+
+    fn get_remote_file(&self, fid: &str) -> Result<DriveFileResponse, DriveError> {
+        let mut resp = try!(self.client
+            .get(&format!("https://www.googleapis.com/drive/v3/files/{}?fields={}"
+                         , fid, DriveFileResponse::fields()))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        json::decode(&resp_string).map_err(From::from)
+    }
+
+    // replaces the local copy of a file with its contents on Drive, as of `remote`
+    fn download_remote_content(&self, uuid: &Uuid, fid: &str, mime_type: &str, path: &str, remote: &RemoteVersionResponse) -> Result<(), DriveError> {
+        let mut resp = try!(self.client
+            .get(&try!(self.content_url(fid, mime_type)))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+        if !resp.status.is_success() {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        let mut f = try!(File::create(path));
+        let size = try!(io::copy(&mut resp, &mut f));
+        let checksum = try!(get_file_checksum(Path::new(path)));
+
+        try!(self.conn.execute("UPDATE files
+                                SET checksum=$1, size=$2, version=$3, head_revision=$4
+                                WHERE uuid=$5",
+            &[ &checksum,
+               &(size as i64),
+               &remote.version,
+               &remote.headRevisionId,
+               &uuid.clone().as_bytes().to_vec(),
+             ]
+        ));
+
+        Ok(())
+    }
+
+    // sends the whole file at `path` through an upload session, resuming from wherever
+    // Drive says the session left off
+    fn send_upload(&self, session_uri: &str, path: &str) -> Result<UploadedFileResponse, DriveError> {
+        let mut fh = try!(File::open(path));
+        let total = try!(fh.metadata()).len();
+
+        let mut progress = try!(self.query_upload_session(session_uri, total));
+        loop {
+            let offset = match progress {
+                UploadProgress::Complete(resp_string) => return json::decode(&resp_string).map_err(From::from),
+                UploadProgress::Received(offset) => offset,
+            };
+            if offset >= total {
+                // everything was sent, but Drive hasn't acknowledged the upload as finished
+                return Err(DriveError {
+                    kind: DriveErrorType::UploadFailed,
+                    response: None,
+                })
+            }
+
+            let len = cmp::min(self.config.upload_chunk_size, total - offset);
+            println!("uploading bytes {}-{} of {}", offset, offset + len - 1, total);
+
+            try!(fh.seek(SeekFrom::Start(offset)));
+            let mut chunk = Read::by_ref(&mut fh).take(len);
+            progress = try!(self.client
+                .put(session_uri)
+                .header(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((offset, offset + len - 1)),
+                    instance_length: Some(total),
+                }))
+                .body(Body::SizedBody(&mut chunk, len))
+                .send()
+                .map_err(From::from)
+                .and_then(upload_progress));
+        }
+    }
+
+    fn start_upload_session(&self, method: Method, url: &str, metadata: &str, content_type: Option<&str>, total: u64) -> Result<String, DriveError> {
+        let mut req = self.client
+            .request(method, url)
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
+            .header(XUploadContentLength(total));
+        if let Some(content_type) = content_type {
+            req = req.header(XUploadContentType(content_type.to_string()));
+        }
+        let mut resp = try!(req.body(metadata).send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: upload_error_type(&resp_string),
+                response: Some(resp_string),
+            })
+        }
+
+        match resp.headers.get::<Location>() {
+            Some(&Location(ref uri)) => Ok(uri.clone()),
+            None => Err(DriveError {
+                kind: DriveErrorType::UploadFailed,
+                response: Some(resp_string),
+            })
+        }
+    }
+
+    // an empty PUT with an unknown range asks Drive how many bytes of the upload it has
+    fn query_upload_session(&self, session_uri: &str, total: u64) -> Result<UploadProgress, DriveError> {
+        self.client
+            .put(session_uri)
+            .header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(total),
+            }))
+            .body("")
+            .send()
+            .map_err(From::from)
+            .and_then(upload_progress)
+    }
+
+    /// The name a file goes by in the mount, which for a Google file is the name of its
+    /// export or link file. Google files that aren't exported have no name, and aren't shown.
+    fn local_name(&self, fr: &DriveFileResponse) -> Option<String> {
+        if !is_google_apps_type(&fr.mimeType) || fr.mimeType == SHORTCUT_MIME_TYPE {
+            return Some(fr.name.clone())
+        }
+
+        match self.config.docs_mode.link_extension() {
+            Some(extension) => Some(export_name(&fr.name, extension)),
+            None => self.export_file_name(&fr.name, &fr.mimeType),
+        }
+    }
+
+    // the name of the export of a Google file, None if it has no export format
+    fn export_file_name(&self, name: &str, mime_type: &str) -> Option<String> {
+        self.config.export_formats.get(mime_type)
+            .map(|extension| export_name(name, extension))
+    }
+
+    // the checksum and size Drive has for a file. These come along with every listing, so
+    // Drive is only asked for them directly when the file has a version newer than the
+    // cached ones, or the listing didn't have them
+    fn remote_checksum(&self, uuid: &Uuid, fid: &str) -> Result<FileCheckResponse, DriveError> {
+        let version = self.uuid_map.get(uuid).and_then(|fr| fr.version.clone());
+        if let Some(fcr) = try!(cached_remote_checksum(&self.conn, uuid, version.as_ref().map(|v| v.as_str()))) {
+            return Ok(fcr)
+        }
+
+        let mut resp = try!(self.client
+            .get(&format!(
+                "https://www.googleapis.com/drive/v3/files/{}\
+                ?fields=md5Checksum%2Csize%2Cversion"
+                , fid))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        let fcr: FileCheckResponse = try!(json::decode(&resp_string));
+
+        try!(self.conn.execute("UPDATE files
+                                SET remote_checksum=$1, remote_size=$2, remote_version=$3
+                                WHERE uuid=$4",
+            &[ &fcr.md5Checksum,
+               &(fcr.size as i64),
+               &fcr.version,
+               &uuid.clone().as_bytes().to_vec(),
+             ]
+        ));
+
+        Ok(fcr)
+    }
+
+    // where the contents of a file can be downloaded from, which for a Google file is its
+    // export in the configured format
+    fn content_url(&self, fid: &str, mime_type: &str) -> Result<String, DriveError> {
+        if !is_google_apps_type(mime_type) {
+            return Ok(format!("https://www.googleapis.com/drive/v3/files/{}?alt=media", fid))
+        }
+
+        let export_type = try!(self.config.export_formats.get(mime_type)
+            .and_then(|extension| export_mime_type(mime_type, extension))
+            .ok_or(DriveError {
+                kind: DriveErrorType::UnsupportedDocumentType,
+                response: None,
+            }));
+        Ok(format!("https://www.googleapis.com/drive/v3/files/{}/export?mimeType={}"
+                  , fid
+                  , utf8_percent_encode(export_type, DRIVE_PARAM_ENCODE_SET{})))
+    }
+
+    /// The MIME type a Google file's export is uploaded as, when edits to the export are
+    /// converted back into the Google file. None for files whose exports are read-only.
+    fn import_type(&self, mime_type: &str) -> Option<&'static str> {
+        if !is_google_apps_type(mime_type)
+        || self.config.docs_mode != GoogleDocsMode::Export
+        || !self.config.reimport_types.contains(mime_type) {
+            return None
+        }
+
+        self.config.export_formats.get(mime_type)
+            .and_then(|extension| export_mime_type(mime_type, extension))
+    }
+
+    // link files are made from the metadata we already have, without downloading anything
+    fn write_link_file(&self, uuid: &Uuid, fr: &DriveFileResponse, file_path: &Path) -> Result<u64, DriveError> {
+        let contents = link_file_contents(self.config.docs_mode, &fr.name, &fr.id, &fr.mimeType
+                                         , fr.webViewLink.as_ref().map(|url| url.as_str()));
+
+        let mut f = try!(File::create(file_path));
+        try!(f.write_all(contents.as_bytes()));
+        let checksum = try!(get_file_checksum(file_path));
+
+        try!(self.conn.execute("UPDATE files
+                                SET checksum=$1, size=$2, version=$3, head_revision=$4
+                                WHERE uuid=$5",
+            &[ &checksum,
+               &(contents.len() as i64),
+               &fr.version,
+               &fr.headRevisionId,
+               &uuid.clone().as_bytes().to_vec(),
+             ]
+        ));
+
+        Ok(contents.len() as u64)
+    }
+
+    // Google files have no content or checksum of their own, so the local export is only
+    // redone when the file's version changes
+    fn retreive_export(&self, uuid: &Uuid, fr: &DriveFileResponse, file_path: &Path) -> Result<u64, DriveError> {
+        let version = try!(self.conn.query_row_named("SELECT version FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> Option<String> { row.get(0) }
+        ));
+        if version.is_some() && version == fr.version {
+            if let Ok(metadata) = std::fs::metadata(file_path) {
+                return Ok(metadata.len())
+            }
+        }
+
+        println!("exporting {}", fr.name);
+        let mut resp = try!(self.client
+            .get(&try!(self.content_url(&fr.id, &fr.mimeType)))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+        if !resp.status.is_success() {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        let mut f = try!(File::create(file_path));
+        let size = try!(io::copy(&mut resp, &mut f));
+        let checksum = try!(get_file_checksum(file_path));
+
+        try!(self.conn.execute("UPDATE files
+                                SET checksum=$1, size=$2, version=$3, head_revision=$4
+                                WHERE uuid=$5",
+            &[ &checksum,
+               &(size as i64),
+               &fr.version,
+               &fr.headRevisionId,
+               &uuid.clone().as_bytes().to_vec(),
+             ]
+        ));
+
+        Ok(size)
+    }
+
     fn download_file(&self, uuid: &Uuid, parent_uuid: &Uuid) -> Result<DownloadedFileInformation, DriveError> {
         let fr = try!(self.uuid_map.get(uuid).ok_or(DriveError {
             kind: DriveErrorType::FailedUuidLookup,
@@ -118,14 +1242,8 @@ impl DriveFileDownloader {
             response: None,
         })).path.clone();
 
-        if fr.mimeType == "application/vnd.google-apps.document"
-        || fr.mimeType == "application/vnd.google-apps.form"
-        || fr.mimeType == "application/vnd.google-apps.drawing"
-        || fr.mimeType == "application/vnd.google-apps.fusiontable"
-        || fr.mimeType == "application/vnd.google-apps.map"
-        || fr.mimeType == "application/vnd.google-apps.presentation"
-        || fr.mimeType == "application/vnd.google-apps.spreadsheet"
-        || fr.mimeType == "application/vnd.google-apps.sites" {
+        // Google files are exported by retreive_export instead
+        if is_google_apps_type(&fr.mimeType) {
             println!("unsupported google filetype");
             return Err(DriveError {
                 kind: DriveErrorType::UnsupportedDocumentType,
@@ -137,7 +1255,10 @@ impl DriveFileDownloader {
             kind: DriveErrorType::NoPathForParent,
             response: None,
         }));
-        file_path.push(fr.name.clone());
+        file_path.push(try!(self.local_name(&fr).ok_or(DriveError {
+            kind: DriveErrorType::UnsupportedDocumentType,
+            response: None,
+        })));
 
         // we'll checksum the file (if we have it) on the system, and compare it to the
         // server, if all is OK, create a new metadata file
@@ -161,8 +1282,10 @@ impl DriveFileDownloader {
                         ?alt=media", fr.id.clone()))
                                  .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
                                  .send());
+            try!(io::copy(&mut resp, &mut f));
 
-            let check_response = try!(self.verify_checksum(uuid, None));
+            let checksum = try!(get_file_checksum(&file_path));
+            let check_response = try!(self.verify_checksum(uuid, Some(&checksum)));
             let size = check_response.size;
 
             println!("md5: {}, size: {}", check_response.md5Checksum, size);
@@ -222,6 +1345,11 @@ define_encode_set! {
     pub DRIVE_QUERY_ENCODE_SET = [QUERY_ENCODE_SET] | {':'}
 }
 
+define_encode_set! {
+    /// Used for values, like MIME types, that go into a single query parameter.
+    pub DRIVE_PARAM_ENCODE_SET = [QUERY_ENCODE_SET] | {':', '/', '+', '&', '=', '%'}
+}
+
 impl FileDownloader for DriveFileDownloader {
     fn get_file_list(&mut self, root_folder_uuid: &uuid::Uuid) -> Result<FileUpdates, DriveError> {
         let uuid_vec = root_folder_uuid.clone().as_bytes().to_vec();
@@ -250,10 +1378,11 @@ impl FileDownloader for DriveFileDownloader {
         let query = format!("https://www.googleapis.com/drive/v3/files\
                                 ?corpus=domain\
                                 &pageSize=1000\
-                                &fields=files\
+                                &fields={}\
                                 &q=%27{}%27+in+parents\
                                 +and+modifiedTime%3E'{}'\
                                 +and+trashed+%3D+"
+                                , DriveFileResponse::list_fields()
                                 , parent_id
                                 , lastdate_encoded);
         println!("{}", query);
@@ -309,72 +1438,9 @@ impl FileDownloader for DriveFileDownloader {
                 let mut decoder = Decoder::new(i.clone());
                 let fr: DriveFileResponse = try!(Decodable::decode(&mut decoder));
 
-                let kind = if fr.mimeType == "application/vnd.google-apps.folder" {
-                    FileType::Directory
-                } else {
-                    FileType::RegularFile
-                };
-
-                let mut path = parent_path.clone();
-                path.push(fr.name.clone());
-
-
-                let uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE id=:id"
-                    , &[(":id", &fr.id)]
-                    , |row| -> Uuid {
-                        Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).expect("failed to parse Uuid from drive db storage")
-                    }
-                ).and_then(|uuid| -> Result<Uuid, rusqlite::Error> {
-                    self.conn.execute("UPDATE files
-                                       SET path=$1, mimetype=$2
-                                       WHERE uuid=$3"
-                        , &[ &(path.to_str().expect("nilfalsdfs"))
-                           , &fr.mimeType
-                           , &uuid.clone().as_bytes().to_vec()
-                           ]
-                    ).unwrap_or_else(|err| {
-                        println!("couldn't update file in drive db, err: {:?}", err);
-                        0
-                    });
-
-                    Ok(uuid)
-                }).unwrap_or_else(|_| {
-                    let uuid = Uuid::new_v4();
-                    self.conn.execute("INSERT INTO files (uuid, id, mimetype, path)
-                                       VALUES ($1, $2, $3, $4)"
-                        , &[ &uuid.clone().as_bytes().to_vec()
-                           , &fr.id
-                           , &fr.mimeType
-                           , &(path.to_str().expect("fadsfnjfsad"))
-                           ]
-                    ).unwrap_or_else(|_| {
-                        println!("file already in drive db: {}", fr.name);
-                        0
-                    });
-
-                    uuid
-                });
-
-                {
-                    self.uuid_map.insert(uuid, fr.clone());
+                if let Some(file) = self.listed_file(fr, &parent_id, &parent_path, root_folder_uuid) {
+                    files_list.push(file)
                 }
-
-                assert!(fr.parents.len() == 1);
-                let parent_uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE id=:id"
-                    , &[( ":id", &fr.parents[0] )]
-                    , |row| -> Uuid {
-                        Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).expect("failed to parse parent Uuid from drive db storage")
-                    }
-                ).unwrap();
-
-    //            println!("drive adding path: {:?} {:?}", uuid.clone().as_bytes().to_vec(), path);
-                files_list.push(FileResponse {
-                    uuid: uuid,
-                    parent_uuid: parent_uuid,
-                    kind: kind,
-                    name: fr.name.clone(),
-                    source_data: SourceData::Drive(fr)
-                });
             }
 
             try!(self.conn.execute("INSERT INTO meta (uuid, last_update, num_files_updated)
@@ -406,16 +1472,31 @@ impl FileDownloader for DriveFileDownloader {
             kind: DriveErrorType::FailedUuidLookup,
             response: None,
         })).clone();
-        let parent_path = try!(self.uuid_map.get(parent_uuid).ok_or(DriveError {
-            kind: DriveErrorType::FailedUuidLookup,
-            response: None,
-        })).path.clone();
+        let (parent_id, parent_path) = {
+            let parent = try!(self.uuid_map.get(parent_uuid).ok_or(DriveError {
+                kind: DriveErrorType::FailedUuidLookup,
+                response: None,
+            }));
+            (parent.id.clone(), parent.path.clone())
+        };
 
         let mut file_path = try!(parent_path.ok_or(DriveError {
             kind: DriveErrorType::NoPathForParent,
             response: None,
         }));
-        file_path.push(fr.name.clone());
+        file_path.push(try!(self.local_name(&fr).ok_or(DriveError {
+            kind: DriveErrorType::UnsupportedDocumentType,
+            response: None,
+        })));
+
+        // the filetree asks for a file from the folder it has it in, which is where its
+        // local copy is kept, whichever of its parents on Drive comes first
+        try!(self.conn.execute("UPDATE files SET path=$1, parent_id=$2 WHERE uuid=$3"
+            , &[ &(file_path.to_str().expect("non-utf8 path"))
+               , &parent_id
+               , &uuid.clone().as_bytes().to_vec()
+               ]
+        ));
 
 //        let (path, mimeType) = try!(self.conn.query_row_named("SELECT path, mimetype FROM files WHERE uuid=:uuid"
 //            , &[(":uuid", &uuid.clone().as_bytes().to_vec())]
@@ -425,7 +1506,15 @@ impl FileDownloader for DriveFileDownloader {
         {
             let fr = self.uuid_map.get_mut(uuid).unwrap();
             fr.path = Some(file_path.clone());
-            if fr.mimeType == "application/vnd.google-apps.folder" {
+            if fr.mimeType == SHORTCUT_MIME_TYPE {
+                // shortcuts are only links to another file, with nothing to download
+                return Ok(0)
+            }
+            if let Some(target) = symlink_target(fr) {
+                // the target we'd download is already in the file's metadata
+                return Ok(target.len() as u64)
+            }
+            if fr.mimeType == FOLDER_MIME_TYPE {
                 let mut dir_builder = DirBuilder::new();
                 dir_builder.recursive(true);
                 // create the directory in the system filesystem
@@ -434,6 +1523,41 @@ impl FileDownloader for DriveFileDownloader {
             }
         }
 
+        // changes to a read-only copy of a Google file can't go anywhere, so they're dropped
+        if is_google_apps_type(&fr.mimeType) && self.import_type(&fr.mimeType).is_none() {
+            try!(upload::dequeue_upload(&self.conn, uuid));
+        }
+
+        // local changes that haven't been uploaded yet mustn't be overwritten by whatever
+        // is on Drive, unless the conflict policy says so
+        if try!(upload::is_dirty(&self.conn, uuid)) {
+            let remote = RemoteVersionResponse {
+                name: fr.name.clone(),
+                parents: fr.parents.clone(),
+                version: fr.version.clone(),
+                headRevisionId: fr.headRevisionId.clone(),
+            };
+            let path = try!(file_path.to_str().ok_or(DriveError {
+                kind: DriveErrorType::NoFileName,
+                response: None,
+            })).to_string();
+
+            if !try!(self.resolve_conflict(uuid, &fr.id, &fr.mimeType, &path, &remote)) {
+                // the local changes were either dropped or moved to a conflict copy
+                try!(upload::dequeue_upload(&self.conn, uuid));
+            }
+
+            return Ok(try!(std::fs::metadata(&file_path)).len())
+        }
+
+        if is_google_apps_type(&fr.mimeType) {
+            if self.config.docs_mode == GoogleDocsMode::Export {
+                return self.retreive_export(uuid, &fr, &file_path)
+            } else {
+                return self.write_link_file(uuid, &fr, &file_path)
+            }
+        }
+
         let (id, maybe_checksum) = try!(self.conn.query_row_named("SELECT id, checksum FROM files WHERE uuid=:uuid"
             , &[(":uuid", &uuid.clone().as_bytes().to_vec())]
             , |row| -> (String, Option<String>) {
@@ -477,118 +1601,166 @@ impl FileDownloader for DriveFileDownloader {
 
         println!("shpu;d ne updating");
 
+        // the local copy now matches this version of the file on Drive, which is what any
+        // later upload will be checked against
+        try!(self.conn.execute("UPDATE files
+                                SET version=$1, head_revision=$2
+                                WHERE uuid=$3",
+            &[ &fr.version,
+               &fr.headRevisionId,
+               &uuid.clone().as_bytes().to_vec(),
+             ]
+        ));
+
         Ok(info.size)
     }
 
     fn create_local_file(&mut self, parent_uuid: &Uuid, name: &Path) -> Result<Uuid, DriveError> {
-        let parent_path = self.conn.query_row_named("SELECT path FROM files WHERE uuid=:uuid"
+        let (parent_id, parent_path) = try!(self.conn.query_row_named("SELECT id, path FROM files WHERE uuid=:uuid"
             , &[ (":uuid", &parent_uuid.as_bytes().to_vec()) ]
-            , |row| -> String {
-                row.get(0)
+            , |row| -> (String, String) {
+                (row.get(0), row.get(1))
             }
-        ).unwrap();
-        let file_path = parent_path + &name.to_str().unwrap();
+        ));
+        let file_path = Path::new(&parent_path).join(name);
 
         try!(File::create(&file_path));
         let uuid = Uuid::new_v4();
 
-        let id_from_drive = String::new();
+        // the file gets its ID once the uploader has created it on Drive
+        let id_from_drive = String::new();
+
+        try!(self.conn.execute("INSERT INTO files (id, uuid, path, parent_id)
+                                VALUES ($1, $2, $3, $4)"
+            , &[ &id_from_drive,
+                 &uuid.clone().as_bytes().to_vec(),
+                 &file_path.to_str().unwrap(),
+                 &parent_id,
+               ]
+        ));
+        try!(upload::queue_upload(&self.conn, &uuid, self.config.upload_delay));
+
+        Ok(uuid)
+    }
 
-        self.conn.execute("INSERT INTO files (id, uuid, path)
-                           VALUES ($1, $2, $3)"
-            , &[ &id_from_drive,
-                 &uuid.clone().as_bytes().to_vec(),
-                 &file_path
+    fn create_symlink(&mut self, parent_uuid: &Uuid, name: &Path, target: &Path) -> Result<Uuid, DriveError> {
+        let target = try!(target.to_str().ok_or(DriveError {
+            kind: DriveErrorType::NoFileName,
+            response: None,
+        })).to_string();
+        let uuid = try!(self.create_local_file(parent_uuid, name));
+
+        // the target doubles as the file's contents, so the link reads sensibly in Drive's
+        // web UI, and on machines that don't run driver
+        let path = try!(self.conn.query_row_named("SELECT path FROM files WHERE uuid=:uuid"
+            , &[ (":uuid", &uuid.as_bytes().to_vec()) ]
+            , |row| -> String { row.get(0) }
+        ));
+        let mut f = try!(File::create(&path));
+        try!(f.write_all(target.as_bytes()));
+
+        try!(self.conn.execute("UPDATE files
+                                SET link_path=$1, mimetype='text/plain'
+                                WHERE uuid=$2"
+            , &[ &target
+               , &uuid.clone().as_bytes().to_vec()
                ]
-        ).unwrap();
+        ));
 
         Ok(uuid)
     }
 
-    fn read_file(&self, uuid: &Uuid) -> Result<Vec<u8>, DriveError> {
-        let path = self.conn.query_row_named("SELECT path FROM files WHERE uuid=:uuid",
+    fn local_path(&self, uuid: &Uuid) -> Result<PathBuf, DriveError> {
+        let path = try!(self.conn.query_row_named("SELECT path FROM files WHERE uuid=:uuid",
             &[(":uuid", &uuid.clone().as_bytes().to_vec())]
             , |row| -> String { row.get(0) }
-        ).expect("failure in retrieve_file sql");
+        ));
 
-        let mut handle = try!(File::open(&path));
-        let mut data = Vec::<u8>::new();
-        match handle.read_to_end(&mut data) {
-            Ok(_) => (),
-            Err(error) => println!("couldnt read file handle, {}: error, {}", path, error),
-        };
+        Ok(PathBuf::from(path))
+    }
+
+    fn mark_dirty(&self, uuid: &Uuid) -> Result<(), DriveError> {
+        // Drive sets its own modifiedTime when the upload lands, so the time of the write
+        // goes along with it, to be restored on the next mount
+        try!(self.conn.execute("UPDATE files SET posix_mtime=$1 WHERE uuid=$2"
+            , &[ &posix::format_timespec(time::get_time())
+               , &uuid.clone().as_bytes().to_vec()
+               ]
+        ));
 
-        Ok(data)
+        // the actual upload is left to the background uploader
+        upload::queue_upload(&self.conn, uuid, self.config.upload_delay)
     }
 
-    fn write_file(&self, uuid: &Uuid, data: &[u8], offset: u64) -> Result<u32, DriveError> {
-        let path = self.conn.query_row_named("SELECT path FROM files WHERE uuid=:uuid"
-            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
-            , |row| -> String {
-                row.get(0)
-            }
-        ).unwrap();
+    fn delay_upload(&self, uuid: &Uuid) -> Result<(), DriveError> {
+        upload::delay_upload(&self.conn, uuid, self.config.upload_delay)
+    }
 
-        let mut fh = OpenOptions::new()
-            .write(true)
-            .truncate(false)
-            .create(false)
-            .open(path)
-            .unwrap();
-        try!(fh.seek(SeekFrom::Start(offset)));
-        try!(fh.write_all(data));
-        try!(fh.set_len(offset+(data.len() as u64)));
+    fn set_posix_attrs(&mut self, uuid: &Uuid, attrs: &PosixAttrs) -> Result<(), DriveError> {
+        try!(self.conn.execute("UPDATE files
+                                SET posix_mode=$1, posix_uid=$2, posix_gid=$3, posix_mtime=$4
+                                WHERE uuid=$5"
+            , &[ &attrs.mode.map(|mode| mode as i64)
+               , &attrs.uid.map(|uid| uid as i64)
+               , &attrs.gid.map(|gid| gid as i64)
+               , &attrs.mtime.map(posix::format_timespec)
+               , &uuid.clone().as_bytes().to_vec()
+               ]
+        ));
 
-        Ok(data.len() as u32)
+        upload::queue_metadata_update(&self.conn, uuid, self.config.upload_delay)
     }
 
-    fn flush_file(&self, uuid: &Uuid) -> Result<(), DriveError> {
-        let (fid, path) = self.conn.query_row_named("SELECT id, path FROM files WHERE uuid=:uuid"
+    fn link_target(&self, uuid: &Uuid) -> Result<LinkTarget, DriveError> {
+        let (target_id, link_path) = try!(self.conn.query_row_named("SELECT target_id, link_path FROM files WHERE uuid=:uuid"
             , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
-            , |row| -> (String, String) {
-                (row.get(0),
-                 row.get(1)
-                )
-            }
-        ).unwrap();
+            , |row| -> (Option<String>, Option<String>) { (row.get(0), row.get(1)) }
+        ));
+        if let Some(link_path) = link_path {
+            return Ok(LinkTarget::Path(link_path))
+        }
 
-        let mut fh = OpenOptions::new()
-            .read(true)
-            .write(false)
-            .open(path)
-            .unwrap();
-        let mut data = Vec::<u8>::new();
-        try!(fh.read_to_end(&mut data));
-
-        let resp = try!(self.client
-            .patch(&format!("https://www.googleapis.com/upload/drive/v3/files/{}", fid))
-            .body(&data as &[u8])
-            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
-            .send());
+        let target_id = try!(target_id.ok_or(DriveError {
+            kind: DriveErrorType::NotALink,
+            response: None,
+        }));
 
-        let fcr = try!(self.verify_checksum(&uuid, None));
-        self.conn.execute("UPDATE files
-                           SET checksum=$1, size=$2
-                           WHERE uuid=$3",
-            &[ &fcr.md5Checksum,
-               &(fcr.size as i64),
-               &uuid.clone().as_bytes().to_vec(),
-             ]
-         ).unwrap();
+        // we won't know the target if it's somewhere outside of the mount
+        let target_uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE id=:id"
+            , &[( ":id", &target_id )]
+            , |row| -> Uuid {
+                Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).expect("failed to parse Uuid from drive db storage")
+            }
+        ).ok();
 
-        Ok(())
+        Ok(LinkTarget::Shortcut {
+            target_id: target_id,
+            target_uuid: target_uuid,
+        })
     }
 
     fn verify_checksum<'a>(&self, uuid: &Uuid, checksum: Option<&'a str>) -> Result<FileCheckResponse, DriveError> {
-        let (fid, path) = self.conn.query_row_named("SELECT id, path FROM files WHERE uuid=:uuid"
+        let (fid, path, mime_type) = self.conn.query_row_named("SELECT id, path, mimetype FROM files WHERE uuid=:uuid"
             , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
-            , |row| -> (String, String) {
+            , |row| -> (String, String, Option<String>) {
                 (row.get(0),
-                 row.get(1)
+                 row.get(1),
+                 row.get(2)
                 )
             }
         ).unwrap();
 
+        if mime_type.map(|mime_type| is_google_apps_type(&mime_type)).unwrap_or(false) {
+            // Drive has no checksum for a Google file, only for what it was exported as,
+            // which retreive_file keeps up to date with the file's version
+            let checksum = try!(get_file_checksum(Path::new(&path)));
+            return Ok(FileCheckResponse {
+                md5Checksum: checksum,
+                size: try!(std::fs::metadata(&path)).len(),
+                version: None,
+            })
+        }
+
         let checksum: Cow<'a, str> = match checksum {
             Some(sum) => Cow::Borrowed(sum),
             None => {
@@ -604,17 +1776,7 @@ impl FileDownloader for DriveFileDownloader {
             }
         };
 
-        let mut resp = try!(self.client
-            .get(&format!(
-                "https://www.googleapis.com/drive/v3/files/{}\
-                ?fields=md5Checksum%2Csize"
-                , fid.clone()))
-            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
-            .send());
-
-        let mut resp_string = String::new();
-        try!(resp.read_to_string(&mut resp_string));
-        let fcr: FileCheckResponse = try!(json::decode(&resp_string));
+        let fcr = try!(self.remote_checksum(uuid, &fid));
         let same = &checksum == &fcr.md5Checksum;
         if !same {
             println!("{} =? {}", checksum, fcr.md5Checksum);
@@ -622,13 +1784,448 @@ impl FileDownloader for DriveFileDownloader {
             println!("size: {}", fcr.size);
             return Err(DriveError {
                 kind: DriveErrorType::FailedChecksum,
-                response: Some(resp_string)
+                response: None,
             })
         }
 
         Ok(fcr)
     }
 
+    fn validate_file(&mut self, uuid: &Uuid) -> Result<Option<u64>, DriveError> {
+        let ttl = match self.config.open_validation {
+            OpenValidation::Never => return Ok(None),
+            OpenValidation::Ttl(ttl) => Some(ttl as i64),
+            OpenValidation::Always => None,
+        };
+
+        let (fid, path, mime_type, link_path, checksum, version, validated_at) = try!(self.conn.query_row_named(
+            "SELECT id, path, mimetype, link_path, checksum, version, validated_at FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> (String, String, Option<String>, Option<String>, Option<String>, Option<String>, Option<i64>) {
+                (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4), row.get(5), row.get(6))
+            }
+        ));
+        let mime_type = mime_type.unwrap_or(String::new());
+        let now = time::get_time().sec;
+        if let (Some(ttl), Some(validated_at)) = (ttl, validated_at) {
+            if now - validated_at < ttl {
+                return Ok(None)
+            }
+        }
+
+        // files not on Drive yet, and local edits still waiting to be uploaded, have
+        // nothing to be checked against; nor does anything that isn't a download
+        if fid.is_empty()
+        || link_path.is_some()
+        || mime_type == FOLDER_MIME_TYPE
+        || mime_type == SHORTCUT_MIME_TYPE
+        || (is_google_apps_type(&mime_type) && self.config.docs_mode != GoogleDocsMode::Export)
+        || try!(upload::is_dirty(&self.conn, uuid)) {
+            return Ok(None)
+        }
+
+        let fr = try!(self.get_remote_file(&fid));
+        try!(replace_remote_checksum(&self.conn, uuid, &fr.md5Checksum, fr.size, &fr.version));
+        if let Some(cached) = self.uuid_map.get_mut(uuid) {
+            let cached_path = cached.path.clone();
+            *cached = fr.clone();
+            cached.path = cached_path;
+        }
+
+        // Google files have no checksum, so their exports go by the version they were made from
+        let current = if is_google_apps_type(&mime_type) {
+            version.is_some() && version == fr.version
+        } else {
+            checksum.is_some() && checksum == fr.md5Checksum
+        };
+        let size = if current {
+            None
+        } else {
+            println!("{} changed on Drive, downloading it again", path);
+            let remote = RemoteVersionResponse {
+                name: fr.name.clone(),
+                parents: fr.parents.clone(),
+                version: fr.version.clone(),
+                headRevisionId: fr.headRevisionId.clone(),
+            };
+            try!(self.download_remote_content(uuid, &fid, &mime_type, &path, &remote));
+            Some(try!(std::fs::metadata(&path)).len())
+        };
+
+        // only a copy known to be current is trusted for the rest of the ttl
+        try!(self.conn.execute("UPDATE files SET validated_at=$1 WHERE uuid=$2"
+            , &[ &now, &uuid.clone().as_bytes().to_vec() ]
+        ));
+
+        Ok(size)
+    }
+
+    fn get_xattr(&self, uuid: &Uuid, name: &str) -> Result<Vec<u8>, DriveError> {
+        let no_xattr = DriveError {
+            kind: DriveErrorType::NoSuchXattr,
+            response: None,
+        };
+        let xattr = match Xattr::from_name(name) {
+            Some(xattr) => xattr,
+            None => return Err(no_xattr),
+        };
+
+        if xattr == Xattr::Permissions {
+            let permissions = match self.drive_id(uuid) {
+                Ok(fid) => try!(self.list_permissions(&fid)),
+                Err(DriveError { kind: DriveErrorType::NotYetUploaded, .. }) => return Err(no_xattr),
+                Err(err) => return Err(err),
+            };
+            return Ok(permissions.to_json().to_string().into_bytes())
+        }
+
+        let fr = match self.file_metadata(uuid) {
+            Ok(fr) => fr,
+            // a file made locally has nothing on Drive yet
+            Err(DriveError { kind: DriveErrorType::NotYetUploaded, .. }) => return Err(no_xattr),
+            Err(err) => return Err(err),
+        };
+        xattr.value(&fr).map(|value| value.into_bytes()).ok_or(no_xattr)
+    }
+
+    fn list_xattrs(&self, uuid: &Uuid) -> Result<Vec<String>, DriveError> {
+        let fr = match self.file_metadata(uuid) {
+            Ok(fr) => fr,
+            Err(DriveError { kind: DriveErrorType::NotYetUploaded, .. }) => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        Ok(Xattr::all(&fr).iter().map(|xattr| xattr.name()).collect())
+    }
+
+    fn set_xattr(&mut self, uuid: &Uuid, name: &str, value: &[u8]) -> Result<(), DriveError> {
+        let value = try!(str::from_utf8(value).map_err(|_| DriveError {
+            kind: DriveErrorType::InvalidXattrValue,
+            response: None,
+        }));
+        self.set_xattr_value(uuid, name, Some(value))
+    }
+
+    fn remove_xattr(&mut self, uuid: &Uuid, name: &str) -> Result<(), DriveError> {
+        self.set_xattr_value(uuid, name, None)
+    }
+
+    fn list_revisions(&self, uuid: &Uuid) -> Result<Vec<Revision>, DriveError> {
+        let fid = match self.drive_id(uuid) {
+            Ok(fid) => fid,
+            Err(DriveError { kind: DriveErrorType::NotYetUploaded, .. }) => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mime_type = try!(self.conn.query_row_named("SELECT mimetype FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> Option<String> { row.get(0) }
+        ));
+        if mime_type.map(|mime_type| is_google_apps_type(&mime_type)).unwrap_or(false) {
+            return Ok(Vec::new())
+        }
+
+        let mut resp = try!(self.client
+            .get(&format!("https://www.googleapis.com/drive/v3/files/{}/revisions\
+                          ?pageSize=1000\
+                          &fields=revisions(id%2CmodifiedTime%2Csize%2Cmd5Checksum)", fid))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        let list: RevisionListResponse = try!(json::decode(&resp_string));
+        let mut revisions = Vec::new();
+        for revision in list.revisions {
+            if let Some(modified) = parse_rfc3339(&revision.modifiedTime) {
+                let revision = Revision::new(revision.id, modified, revision.size.unwrap_or(0), &revisions);
+                revisions.push(revision);
+            }
+        }
+
+        Ok(revisions)
+    }
+
+    fn retreive_revision(&self, uuid: &Uuid, revision_id: &str) -> Result<PathBuf, DriveError> {
+        let path = Path::new(REVISION_CACHE_DIR).join(revision_id);
+        if path.exists() {
+            return Ok(path)
+        }
+
+        let fid = try!(self.drive_id(uuid));
+        println!("downloading revision {} of {}", revision_id, fid);
+        let mut resp = try!(self.client
+            .get(&format!("https://www.googleapis.com/drive/v3/files/{}/revisions/{}?alt=media", fid, revision_id))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+        if !resp.status.is_success() {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        // downloaded beside the cache first, so a download that's cut off isn't kept
+        try!(DirBuilder::new().recursive(true).create(REVISION_CACHE_DIR));
+        let partial_path = path.with_extension("partial");
+        let mut f = try!(File::create(&partial_path));
+        try!(io::copy(&mut resp, &mut f));
+        try!(std::fs::rename(&partial_path, &path));
+
+        Ok(path)
+    }
+
+    fn list_trash(&self, folder_id: Option<&str>) -> Result<Vec<TrashedFile>, DriveError> {
+        let query = match folder_id {
+            Some(id) => format!("%27{}%27+in+parents+and+trashed%3Dtrue", id),
+            None => "trashed%3Dtrue".to_string(),
+        };
+        let mut trashed = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = format!("https://www.googleapis.com/drive/v3/files\
+                                  ?pageSize=1000\
+                                  &fields=nextPageToken%2Cfiles(id%2Cname%2CmimeType%2Csize%2CmodifiedTime%2CexplicitlyTrashed)\
+                                  &q={}", query);
+            if let Some(ref token) = page_token {
+                url.push_str(&format!("&pageToken={}", utf8_percent_encode(token, DRIVE_PARAM_ENCODE_SET{})));
+            }
+            let mut resp = try!(self.client
+                .get(&url)
+                .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+                .send());
+
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            if !resp.status.is_success() {
+                return Err(DriveError {
+                    kind: DriveErrorType::FailedDownload,
+                    response: Some(resp_string),
+                })
+            }
+
+            let list: TrashListResponse = try!(json::decode(&resp_string));
+            trashed.extend(list.files.into_iter()
+                // the files in a trashed folder are only listed inside it
+                .filter(|file| folder_id.is_some() || file.explicitlyTrashed.unwrap_or(false))
+                .map(|file| TrashedFile {
+                    kind: if file.mimeType == FOLDER_MIME_TYPE { FileType::Directory } else { FileType::RegularFile },
+                    size: file.size.unwrap_or(0),
+                    modified: file.modifiedTime.as_ref().and_then(|t| parse_rfc3339(t)),
+                    id: file.id,
+                    name: file.name,
+                }));
+
+            page_token = list.nextPageToken;
+            if page_token.is_none() {
+                break
+            }
+        }
+
+        Ok(trashed)
+    }
+
+    fn untrash(&mut self, id: &str, parent_uuid: &Uuid, name: &str) -> Result<Option<FileResponse>, DriveError> {
+        let (parent_id, parent_path) = try!(self.conn.query_row_named("SELECT id, path FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &parent_uuid.clone().as_bytes().to_vec() )]
+            , |row| -> (String, PathBuf) {
+                ( row.get(0)
+                , Path::new(&row.get::<i32, String>(1)).to_owned()
+                )
+            }
+        ));
+        // the file is moved out of wherever it was trashed from, like any other move
+        let old_parents = try!(self.get_remote_file(id)).parents.into_iter()
+            .filter(|old_parent| old_parent != &parent_id)
+            .collect::<Vec<String>>();
+
+        let mut metadata = BTreeMap::new();
+        metadata.insert("trashed".to_string(), false.to_json());
+        metadata.insert("name".to_string(), name.to_json());
+        let mut resp = try!(self.client
+            .patch(&format!("https://www.googleapis.com/drive/v3/files/{}\
+                            ?addParents={}\
+                            &removeParents={}\
+                            &fields={}"
+                           , id, parent_id, old_parents.join("%2C"), DriveFileResponse::fields()))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
+            .body(&Json::Object(metadata).to_string())
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::UploadFailed,
+                response: Some(resp_string),
+            })
+        }
+
+        let fr: DriveFileResponse = try!(json::decode(&resp_string));
+        Ok(self.listed_file(fr, &parent_id, &parent_path, parent_uuid))
+    }
+
+    fn delete_forever(&self, id: &str) -> Result<(), DriveError> {
+        let mut resp = try!(self.client
+            .delete(&format!("https://www.googleapis.com/drive/v3/files/{}", id))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+        if !resp.status.is_success() {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            return Err(DriveError {
+                kind: DriveErrorType::UploadFailed,
+                response: Some(resp_string),
+            })
+        }
+
+        Ok(())
+    }
+
+    fn search(&mut self, query: &str, order_by: Option<&str>) -> Result<Vec<SearchResult>, DriveError> {
+        let now = time::get_time().sec;
+        let key = format!("{} orderBy {}", query, order_by.unwrap_or(""));
+        if let Some(&(ref results, searched_at)) = self.searches.get(&key) {
+            if now - searched_at < self.config.search_ttl as i64 {
+                return Ok(results.clone())
+            }
+        }
+
+        let q = format!("({}) and trashed=false", query);
+        let mut url = format!("https://www.googleapis.com/drive/v3/files\
+                              ?pageSize=1000\
+                              &fields=files(id%2Cname%2CmimeType%2Csize%2CmodifiedTime)\
+                              &q={}", utf8_percent_encode(&q, DRIVE_PARAM_ENCODE_SET{}));
+        if let Some(order_by) = order_by {
+            url.push_str(&format!("&orderBy={}", utf8_percent_encode(order_by, DRIVE_PARAM_ENCODE_SET{})));
+        }
+        let mut resp = try!(self.client
+            .get(&url)
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+
+        let mut resp_string = String::new();
+        try!(resp.read_to_string(&mut resp_string));
+        if !resp.status.is_success() {
+            return Err(DriveError {
+                kind: DriveErrorType::InvalidQuery,
+                response: Some(resp_string),
+            })
+        }
+
+        let list: SearchListResponse = try!(json::decode(&resp_string));
+        let results = list.files.into_iter().map(|file| {
+            let uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE id=:id"
+                , &[(":id", &file.id)]
+                , |row| -> Vec<u8> { row.get(0) }
+            ).ok().and_then(|uuid| Uuid::from_bytes(&uuid).ok());
+            // views always read Google files as exports, whatever docs_mode is, and can't
+            // follow shortcuts
+            let local_name = if file.mimeType == SHORTCUT_MIME_TYPE {
+                None
+            } else if is_google_apps_type(&file.mimeType) {
+                self.export_file_name(&file.name, &file.mimeType)
+            } else {
+                Some(file.name.clone())
+            };
+            SearchResult {
+                kind: if file.mimeType == FOLDER_MIME_TYPE { FileType::Directory } else { FileType::RegularFile },
+                local_name: local_name,
+                mime_type: file.mimeType,
+                size: file.size.unwrap_or(0),
+                modified: file.modifiedTime.as_ref().and_then(|t| parse_rfc3339(t)),
+                id: file.id,
+                name: file.name,
+                uuid: uuid,
+            }
+        }).collect::<Vec<SearchResult>>();
+
+        // searches that are out of date would only be run again anyway
+        let ttl = self.config.search_ttl as i64;
+        self.searches.retain(|_, &mut (_, searched_at)| now - searched_at < ttl);
+        self.searches.insert(key, (results.clone(), now));
+        Ok(results)
+    }
+
+    fn retreive_remote(&self, id: &str, mime_type: &str) -> Result<PathBuf, DriveError> {
+        println!("downloading {} to read through a view", id);
+        let mut resp = try!(self.client
+            .get(&try!(self.content_url(id, mime_type)))
+            .header(Authorization(Bearer{token: self.auth_data.tr.access_token.clone()}))
+            .send());
+        if !resp.status.is_success() {
+            let mut resp_string = String::new();
+            try!(resp.read_to_string(&mut resp_string));
+            return Err(DriveError {
+                kind: DriveErrorType::FailedDownload,
+                response: Some(resp_string),
+            })
+        }
+
+        // files that are still open keep reading the copy they opened
+        try!(DirBuilder::new().recursive(true).create(REMOTE_CACHE_DIR));
+        let path = Path::new(REMOTE_CACHE_DIR).join(id);
+        let partial_path = path.with_extension("partial");
+        let mut f = try!(File::create(&partial_path));
+        try!(io::copy(&mut resp, &mut f));
+        try!(std::fs::rename(&partial_path, &path));
+
+        Ok(path)
+    }
+
+    fn storage_space(&mut self) -> Result<StorageSpace, DriveError> {
+        let now = time::get_time().sec;
+        if let Some((ref space, checked_at)) = self.space {
+            if now - checked_at < self.config.quota_ttl as i64 {
+                return Ok(space.clone())
+            }
+        }
+
+        let (local_total, local_free) = try!(disk_space(&self.root_path));
+
+        // being offline shouldn't leave the mount without any idea of its free space
+        let quota = match self.get_storage_quota() {
+            Ok(quota) => {
+                if self.quota_offline {
+                    println!("got storage quota from Drive again");
+                    self.quota_offline = false;
+                }
+                Ok(quota)
+            },
+            Err(err) => {
+                if !self.quota_offline {
+                    println!("couldn't get storage quota from Drive, going by the last one fetched, if any, until it can, err: {:?}", err);
+                    self.quota_offline = true;
+                }
+                match self.quota {
+                    Some((ref quota, _)) => Ok(quota.clone()),
+                    None => Err(err),
+                }
+            }
+        };
+        let (quota_limit, quota_usage) = match quota {
+            Ok(quota) => (quota.limit, quota.usage.unwrap_or(0)),
+            Err(_) => (None, 0),
+        };
+
+        let space = StorageSpace {
+            quota_limit: quota_limit,
+            quota_usage: quota_usage,
+            local_total: local_total,
+            local_free: local_free,
+        };
+        self.space = Some((space.clone(), now));
+        Ok(space)
+    }
+
     fn resolve_error(&mut self, resp_string: &str) -> Result<(), DriveError> {
         println!("attempting to resolve error response: {}", resp_string);
 
@@ -730,6 +2327,13 @@ impl FileDownloader for DriveFileDownloader {
     }
 }
 
+// the target of a symlink made by driver, which is kept in the file's app properties
+fn symlink_target(fr: &DriveFileResponse) -> Option<String> {
+    fr.appProperties.as_ref()
+        .and_then(|properties| properties.get(SYMLINK_APP_PROPERTY))
+        .cloned()
+}
+
 pub fn request_new_access_code(c: &Client) -> Result<TokenResponse, DriveError> {
     // the space after client_id={} is necessary to seperate the link from the rest of the
     // prompt
@@ -759,6 +2363,60 @@ pub fn request_new_access_code(c: &Client) -> Result<TokenResponse, DriveError>
     json::decode(&resp_string).map_err(From::from)
 }
 
+/// The total and available bytes of the filesystem `path` is on.
+pub fn disk_space(path: &Path) -> Result<(u64, u64), DriveError> {
+    let c_path = try!(CString::new(path.as_os_str().as_bytes()).map_err(|_| DriveError {
+        kind: DriveErrorType::NoFileName,
+        response: None,
+    }));
+
+    unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut stat) != 0 {
+            return Err(From::from(io::Error::last_os_error()))
+        }
+        Ok((stat.f_blocks as u64 * stat.f_frsize as u64, stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+}
+
+/// Parses the RFC 3339 timestamps Drive uses, e.g. `2016-05-21T17:06:01.428Z`.
+pub fn parse_rfc3339(s: &str) -> Option<Timespec> {
+    // the time is either in UTC, or followed by its offset from it as `+hh:mm` or `-hh:mm`
+    let (s, utcoff) = if s.ends_with('Z') {
+        (&s[..s.len() - 1], 0)
+    } else if s.len() > 6 && s.bytes().rev().take(6).all(|b| b < 0x80) {
+        let (s, offset) = s.split_at(s.len() - 6);
+        let sign = match &offset[..1] {
+            "+" => 1,
+            "-" => -1,
+            _ => return None,
+        };
+        match (offset[1..3].parse::<i64>(), &offset[3..4], offset[4..].parse::<i64>()) {
+            (Ok(hours), ":", Ok(minutes)) if hours < 24 && minutes < 60 => (s, sign * (hours * 3600 + minutes * 60)),
+            _ => return None,
+        }
+    } else {
+        return None
+    };
+    let mut parts = s.splitn(2, '.');
+    let tm = match parts.next().and_then(|secs| time::strptime(secs, "%Y-%m-%dT%H:%M:%S").ok()) {
+        Some(tm) => tm,
+        None => return None,
+    };
+    // fractions of a second are scaled up to nanoseconds, whatever their precision
+    let nsec = match parts.next() {
+        Some(frac) if !frac.is_empty() && frac.len() <= 9 => match frac.parse::<i32>() {
+            Ok(n) => n * 10i32.pow(9 - frac.len() as u32),
+            Err(_) => return None,
+        },
+        Some(_) => return None,
+        None => 0,
+    };
+
+    let ts = tm.to_timespec();
+    Some(Timespec::new(ts.sec - utcoff, nsec))
+}
+
 pub fn convert_timespec_to_tm(ts: Timespec) -> Tm {
     let time_duration = ts - Timespec::new(0,0);
     Tm {
@@ -775,3 +2433,119 @@ pub fn convert_timespec_to_tm(ts: Timespec) -> Tm {
         tm_nsec:   0,
     } + time_duration
 }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use uuid::Uuid;
+    use super::*;
+
+    fn checksum_db(uuid: &Uuid, checksum: Option<&str>, version: Option<&str>) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE files (uuid BLOB, remote_checksum TEXT, remote_size INTEGER, remote_version TEXT)", &[]).unwrap();
+        conn.execute("INSERT INTO files (uuid, remote_checksum, remote_size, remote_version) VALUES ($1, $2, 3, $3)"
+            , &[ &uuid.as_bytes().to_vec(), &checksum, &version ]).unwrap();
+        conn
+    }
+
+    #[test]
+    fn cached_checksum_hit() {
+        let uuid = Uuid::new_v4();
+        let conn = checksum_db(&uuid, Some("abc"), Some("7"));
+        let fcr = cached_remote_checksum(&conn, &uuid, Some("7")).unwrap().unwrap();
+        assert_eq!(fcr.md5Checksum, "abc");
+        assert_eq!(fcr.size, 3);
+        // an upload moves the cache ahead of the version we last listed
+        assert!(cached_remote_checksum(&conn, &uuid, Some("6")).unwrap().is_some());
+        assert!(cached_remote_checksum(&conn, &uuid, None).unwrap().is_some());
+    }
+
+    #[test]
+    fn cached_checksum_miss() {
+        let uuid = Uuid::new_v4();
+        let conn = checksum_db(&uuid, None, Some("7"));
+        assert!(cached_remote_checksum(&conn, &uuid, Some("7")).unwrap().is_none());
+        assert!(cached_remote_checksum(&conn, &uuid, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn cached_checksum_version_change() {
+        let uuid = Uuid::new_v4();
+        let conn = checksum_db(&uuid, Some("abc"), Some("7"));
+        assert!(cached_remote_checksum(&conn, &uuid, Some("8")).unwrap().is_none());
+
+        // the same version again leaves the cache alone, a new one replaces it
+        replace_remote_checksum(&conn, &uuid, &Some("def".to_string()), Some(4), &Some("7".to_string())).unwrap();
+        assert_eq!(cached_remote_checksum(&conn, &uuid, Some("7")).unwrap().unwrap().md5Checksum, "abc");
+        replace_remote_checksum(&conn, &uuid, &Some("def".to_string()), Some(4), &Some("8".to_string())).unwrap();
+        let fcr = cached_remote_checksum(&conn, &uuid, Some("8")).unwrap().unwrap();
+        assert_eq!(fcr.md5Checksum, "def");
+        assert_eq!(fcr.size, 4);
+    }
+
+    #[test]
+    fn rfc3339_utc() {
+        let ts = parse_rfc3339("2016-05-21T17:06:01Z").unwrap();
+        assert_eq!(ts, Timespec::new(1463850361, 0));
+    }
+
+    #[test]
+    fn rfc3339_fractions() {
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01.123Z"), Some(Timespec::new(1463850361, 123000000)));
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01.000000001Z"), Some(Timespec::new(1463850361, 1)));
+    }
+
+    #[test]
+    fn rfc3339_offsets() {
+        assert_eq!(parse_rfc3339("2016-05-21T19:36:01+02:30"), Some(Timespec::new(1463850361, 0)));
+        assert_eq!(parse_rfc3339("2016-05-21T12:06:01.5-05:00"), Some(Timespec::new(1463850361, 500000000)));
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01+00:00"), Some(Timespec::new(1463850361, 0)));
+    }
+
+    #[test]
+    fn rfc3339_malformed() {
+        assert_eq!(parse_rfc3339(""), None);
+        assert_eq!(parse_rfc3339("yesterday"), None);
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01"), None);
+        assert_eq!(parse_rfc3339("2016-05-21 17:06:01Z"), None);
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01.Z"), None);
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01.12xZ"), None);
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01+2:300"), None);
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01+25:00"), None);
+        assert_eq!(parse_rfc3339("2016-05-21T17:06:01é"), None);
+    }
+
+    fn over_quota(body: &str) -> bool {
+        match upload_error_type(body) {
+            DriveErrorType::QuotaExceeded => true,
+            DriveErrorType::UploadFailed => false,
+            kind => panic!("unexpected error type {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn storage_quota_errors() {
+        let body = r#"{"error": {"errors": [{"domain": "usageLimits", "reason": "storageQuotaExceeded",
+                       "message": "The user's Drive storage quota has been exceeded."}],
+                       "code": 403, "message": "The user's Drive storage quota has been exceeded."}}"#;
+        assert!(over_quota(body));
+
+        let body = r#"{"error": {"errors": [{"domain": "usageLimits", "reason": "quotaExceeded",
+                       "message": "The user has exceeded their Drive storage quota"}],
+                       "code": 403, "message": "The user has exceeded their Drive storage quota"}}"#;
+        assert!(over_quota(body));
+    }
+
+    #[test]
+    fn rate_limits_are_not_over_quota() {
+        let body = r#"{"error": {"errors": [{"domain": "usageLimits", "reason": "userRateLimitExceeded",
+                       "message": "User Rate Limit Exceeded"}],
+                       "code": 403, "message": "User Rate Limit Exceeded"}}"#;
+        assert!(!over_quota(body));
+
+        let body = r#"{"error": {"errors": [{"domain": "usageLimits", "reason": "rateLimitExceeded",
+                       "message": "Quota exceeded for quota metric 'Queries' and limit 'Queries per minute per user'"}],
+                       "code": 403, "message": "Quota exceeded for quota metric 'Queries' and limit 'Queries per minute per user'"}}"#;
+        assert!(!over_quota(body));
+    }
+}
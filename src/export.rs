@@ -0,0 +1,183 @@
+use std::collections::hash_map::HashMap;
+use std::collections::BTreeMap;
+use rustc_serialize::json::{Json, ToJson};
+
+pub const FOLDER_MIME_TYPE: &'static str = "application/vnd.google-apps.folder";
+pub const SHORTCUT_MIME_TYPE: &'static str = "application/vnd.google-apps.shortcut";
+const GOOGLE_APPS_PREFIX: &'static str = "application/vnd.google-apps.";
+
+/// Whether a file is native to Google Drive (a Doc, Sheet, ...), and so has no content of
+/// its own to download.
+pub fn is_google_apps_type(mime_type: &str) -> bool {
+    mime_type.starts_with(GOOGLE_APPS_PREFIX) && mime_type != FOLDER_MIME_TYPE
+}
+
+/// How Google files show up in the mount.
+#[derive (Debug, Clone, Copy, PartialEq)]
+pub enum GoogleDocsMode {
+    // downloaded through files.export, in the format set for each type
+    Export,
+    // small generated files that open the document in a browser, with no download at all
+    GdocLink,
+    DesktopLink,
+    UrlLink,
+}
+
+impl GoogleDocsMode {
+    pub fn from_str(s: &str) -> Option<GoogleDocsMode> {
+        match s {
+            "export"  => Some(GoogleDocsMode::Export),
+            "gdoc"    => Some(GoogleDocsMode::GdocLink),
+            "desktop" => Some(GoogleDocsMode::DesktopLink),
+            "url"     => Some(GoogleDocsMode::UrlLink),
+            _ => None,
+        }
+    }
+
+    pub fn link_extension(&self) -> Option<&'static str> {
+        match *self {
+            GoogleDocsMode::Export      => None,
+            GoogleDocsMode::GdocLink    => Some("gdoc"),
+            GoogleDocsMode::DesktopLink => Some("desktop"),
+            GoogleDocsMode::UrlLink     => Some("url"),
+        }
+    }
+}
+
+/// Builds the contents of a link file pointing at a Google file. `url` falls back to a
+/// link made from the file's ID when Drive didn't give us its webViewLink.
+pub fn link_file_contents(mode: GoogleDocsMode, name: &str, id: &str, mime_type: &str, url: Option<&str>) -> String {
+    let url = url.map(|url| url.to_string())
+                 .unwrap_or(format!("https://drive.google.com/open?id={}", id));
+
+    match mode {
+        GoogleDocsMode::DesktopLink => format!("[Desktop Entry]\n\
+                                               Type=Link\n\
+                                               Name={}\n\
+                                               URL={}\n\
+                                               Icon=text-html\n", name, url),
+        GoogleDocsMode::UrlLink => format!("[InternetShortcut]\r\nURL={}\r\n", url),
+        _ => {
+            let mut d = BTreeMap::new();
+            d.insert("url".to_string(),      url.to_json());
+            d.insert("doc_id".to_string(),   id.to_json());
+            d.insert("name".to_string(),     name.to_json());
+            d.insert("mimeType".to_string(), mime_type.to_json());
+            Json::Object(d).pretty().to_string() + "\n"
+        }
+    }
+}
+
+/// The format each type of Google file is exported as, when the mount isn't told otherwise.
+pub fn default_export_formats() -> HashMap<String, String> {
+    let mut formats = HashMap::new();
+    formats.insert("application/vnd.google-apps.document".to_string(),     "docx".to_string());
+    formats.insert("application/vnd.google-apps.spreadsheet".to_string(),  "xlsx".to_string());
+    formats.insert("application/vnd.google-apps.presentation".to_string(), "pptx".to_string());
+    formats.insert("application/vnd.google-apps.drawing".to_string(),      "svg".to_string());
+    formats
+}
+
+/// Maps the extension of an export format to the MIME type `files.export` expects, for
+/// the formats Drive can export the given type of Google file to.
+pub fn export_mime_type(google_type: &str, extension: &str) -> Option<&'static str> {
+    let mime_type = match (google_type, extension) {
+        ("application/vnd.google-apps.document", "docx") => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        ("application/vnd.google-apps.document", "odt")  => "application/vnd.oasis.opendocument.text",
+        ("application/vnd.google-apps.document", "rtf")  => "application/rtf",
+        ("application/vnd.google-apps.document", "txt")  => "text/plain",
+        ("application/vnd.google-apps.document", "html") => "text/html",
+        ("application/vnd.google-apps.spreadsheet", "xlsx") => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        ("application/vnd.google-apps.spreadsheet", "ods")  => "application/x-vnd.oasis.opendocument.spreadsheet",
+        ("application/vnd.google-apps.spreadsheet", "csv")  => "text/csv",
+        ("application/vnd.google-apps.presentation", "pptx") => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        ("application/vnd.google-apps.presentation", "odp")  => "application/vnd.oasis.opendocument.presentation",
+        ("application/vnd.google-apps.presentation", "txt")  => "text/plain",
+        ("application/vnd.google-apps.drawing", "svg") => "image/svg+xml",
+        ("application/vnd.google-apps.drawing", "png") => "image/png",
+        ("application/vnd.google-apps.drawing", "jpg") => "image/jpeg",
+        ("application/vnd.google-apps.document", "pdf")
+        | ("application/vnd.google-apps.spreadsheet", "pdf")
+        | ("application/vnd.google-apps.presentation", "pdf")
+        | ("application/vnd.google-apps.drawing", "pdf") => "application/pdf",
+        _ => return None,
+    };
+
+    Some(mime_type)
+}
+
+/// The name an exported file shows up under in the mount, e.g. `report` -> `report.docx`.
+pub fn export_name(name: &str, extension: &str) -> String {
+    let suffix = format!(".{}", extension);
+    if name.ends_with(&suffix) {
+        name.to_string()
+    } else {
+        name.to_string() + &suffix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn google_apps_types() {
+        assert!(is_google_apps_type("application/vnd.google-apps.document"));
+        assert!(is_google_apps_type("application/vnd.google-apps.form"));
+        assert!(!is_google_apps_type(FOLDER_MIME_TYPE));
+        assert!(!is_google_apps_type("application/pdf"));
+    }
+
+    #[test]
+    fn export_names() {
+        assert_eq!(export_name("report", "docx"), "report.docx");
+        assert_eq!(export_name("report.docx", "docx"), "report.docx");
+        assert_eq!(export_name("report.txt", "docx"), "report.txt.docx");
+    }
+
+    #[test]
+    fn default_formats_can_be_exported() {
+        let formats = default_export_formats();
+        assert_eq!(formats.len(), 4);
+        for (google_type, extension) in &formats {
+            assert!(export_mime_type(google_type, extension).is_some());
+        }
+    }
+
+    #[test]
+    fn export_mime_types() {
+        assert_eq!(export_mime_type("application/vnd.google-apps.spreadsheet", "csv"), Some("text/csv"));
+        assert_eq!(export_mime_type("application/vnd.google-apps.drawing", "pdf"), Some("application/pdf"));
+        assert_eq!(export_mime_type("application/vnd.google-apps.spreadsheet", "docx"), None);
+        // forms and sites have no pdf export
+        assert_eq!(export_mime_type("application/vnd.google-apps.form", "pdf"), None);
+        assert_eq!(export_mime_type("application/vnd.google-apps.site", "pdf"), None);
+    }
+
+    #[test]
+    fn link_modes() {
+        assert_eq!(GoogleDocsMode::from_str("gdoc"), Some(GoogleDocsMode::GdocLink));
+        assert_eq!(GoogleDocsMode::from_str("link"), None);
+        assert_eq!(GoogleDocsMode::Export.link_extension(), None);
+        assert_eq!(GoogleDocsMode::UrlLink.link_extension(), Some("url"));
+    }
+
+    #[test]
+    fn gdoc_link() {
+        let contents = link_file_contents(GoogleDocsMode::GdocLink, "report", "abc", "application/vnd.google-apps.document", None);
+        let json = Json::from_str(&contents).unwrap();
+        assert_eq!(json.find("url").and_then(|url| url.as_string()), Some("https://drive.google.com/open?id=abc"));
+        assert_eq!(json.find("doc_id").and_then(|id| id.as_string()), Some("abc"));
+        assert_eq!(json.find("name").and_then(|name| name.as_string()), Some("report"));
+        assert_eq!(json.find("mimeType").and_then(|mime| mime.as_string()), Some("application/vnd.google-apps.document"));
+    }
+
+    #[test]
+    fn desktop_and_url_links() {
+        let url = "https://docs.google.com/document/d/abc/edit";
+        assert_eq!(link_file_contents(GoogleDocsMode::DesktopLink, "report", "abc", "application/vnd.google-apps.document", Some(url))
+                  , format!("[Desktop Entry]\nType=Link\nName=report\nURL={}\nIcon=text-html\n", url));
+        assert_eq!(link_file_contents(GoogleDocsMode::UrlLink, "report", "abc", "application/vnd.google-apps.document", Some(url))
+                  , format!("[InternetShortcut]\r\nURL={}\r\n", url));
+    }
+}
@@ -4,16 +4,23 @@ extern crate rusqlite;
 use crypto::md5::Md5;
 use crypto::digest::Digest;
 
+use std::io;
 use std::io::prelude::*;
-use std::fs::{File};
-use std::path::{Path};
+use std::io::SeekFrom;
+use std::os::unix::io::AsRawFd;
+use std::fs::{File, OpenOptions};
+use std::path::{Component, Path, PathBuf};
 use std::collections::hash_map::HashMap;
 use uuid::Uuid;
 
+use libc;
 use time;
+use time::Timespec;
 use fuse::FileAttr;
 
 use types::*;
+use posix;
+use vnode::VirtualNodes;
 
 pub struct FileUpdates {
     pub new_files: Option<Vec<FileResponse>>,
@@ -24,11 +31,67 @@ pub trait FileDownloader {
     fn get_file_list(&mut self, root_folder: &uuid::Uuid) -> Result<FileUpdates, DriveError>;
     fn resolve_error(&mut self, resp_string: &str) -> Result<(), DriveError>;
     fn verify_checksum(&self, fd: &Uuid, checksum: Option<&str>) -> Result<FileCheckResponse, DriveError>;
+    /// Checks the local copy of a file against Drive, as the open_validation option asks,
+    /// and downloads it again if it's out of date. Returns the new size when it was.
+    fn validate_file(&mut self, uuid: &Uuid) -> Result<Option<u64>, DriveError>;
+    fn link_target(&self, uuid: &Uuid) -> Result<LinkTarget, DriveError>;
     fn retreive_file(&mut self, uuid: &Uuid, parent_uuid: &Uuid) -> Result<u64, DriveError>;
     fn create_local_file(&mut self, parent_uuid: &Uuid, file_path: &Path) -> Result<Uuid, DriveError>;
-    fn read_file(&self, uuid: &Uuid) -> Result<Vec<u8>, DriveError>;
-    fn write_file(&self, uuid: &Uuid, data: &[u8], offset: u64) -> Result<u32, DriveError>;
-    fn flush_file(&self, uuid: &Uuid) -> Result<(), DriveError>;
+    fn create_symlink(&mut self, parent_uuid: &Uuid, name: &Path, target: &Path) -> Result<Uuid, DriveError>;
+    fn set_posix_attrs(&mut self, uuid: &Uuid, attrs: &PosixAttrs) -> Result<(), DriveError>;
+    /// Where the local copy of a file is kept.
+    fn local_path(&self, uuid: &Uuid) -> Result<PathBuf, DriveError>;
+    /// Records that the local copy of a file was written to, and so needs uploading.
+    fn mark_dirty(&self, uuid: &Uuid) -> Result<(), DriveError>;
+    /// Restarts the quiet period of a dirty file that's still being written to.
+    fn delay_upload(&self, uuid: &Uuid) -> Result<(), DriveError>;
+    /// How much room is left on Drive, and on the disk with the local copies. Both are
+    /// only checked again once they're older than `quota_ttl`.
+    fn storage_space(&mut self) -> Result<StorageSpace, DriveError>;
+    /// Extended attributes, backed by the file's metadata on Drive; see `xattr::Xattr`.
+    fn get_xattr(&self, uuid: &Uuid, name: &str) -> Result<Vec<u8>, DriveError>;
+    fn list_xattrs(&self, uuid: &Uuid) -> Result<Vec<String>, DriveError>;
+    fn set_xattr(&mut self, uuid: &Uuid, name: &str, value: &[u8]) -> Result<(), DriveError>;
+    fn remove_xattr(&mut self, uuid: &Uuid, name: &str) -> Result<(), DriveError>;
+    /// The past versions of a file on Drive, oldest first. Google files have no versions
+    /// that can be downloaded, and files that were never uploaded have none at all.
+    fn list_revisions(&self, uuid: &Uuid) -> Result<Vec<Revision>, DriveError>;
+    /// Downloads a past version of a file, unless it already was, and returns where it's
+    /// kept.
+    fn retreive_revision(&self, uuid: &Uuid, revision_id: &str) -> Result<PathBuf, DriveError>;
+    /// What's in Drive's trash: the files and folders that were put there, or with
+    /// `folder_id`, what's in one of those folders.
+    fn list_trash(&self, folder_id: Option<&str>) -> Result<Vec<TrashedFile>, DriveError>;
+    /// Takes a file out of the trash and puts it in the folder `parent_uuid`, as `name`.
+    /// The file keeps its ID, and is returned ready to be added to the tree, unless it's
+    /// one that's left out of the mount.
+    fn untrash(&mut self, id: &str, parent_uuid: &Uuid, name: &str) -> Result<Option<FileResponse>, DriveError>;
+    /// Deletes a file in the trash for good.
+    fn delete_forever(&self, id: &str) -> Result<(), DriveError>;
+    /// Runs a Drive query, like `name contains 'invoice'`, over the files that aren't
+    /// trashed, with the results in `order_by` if it's given. The results of a query are
+    /// kept for `search_ttl`.
+    fn search(&mut self, query: &str, order_by: Option<&str>) -> Result<Vec<SearchResult>, DriveError>;
+    /// Downloads a file that isn't in the mount, to be read through a view, and returns
+    /// where it's kept. It's downloaded again each time, as nothing keeps it up to date.
+    /// Google files are exported, in the format set for their type.
+    fn retreive_remote(&self, id: &str, mime_type: &str) -> Result<PathBuf, DriveError>;
+}
+
+// bytes past a sequential read that the kernel is told to read ahead
+const READ_AHEAD_WINDOW: u64 = 1024 * 1024;
+
+/// An open file, kept under the fh it was handed to the kernel as.
+pub struct FileHandle {
+    pub ino: u64,
+    pub uuid: Uuid,
+    // the local copy, held open until the handle is released
+    pub file: File,
+    pub flags: u32,
+    // written to since the last flush, so the file is due to be queued for upload again
+    pub dirty: bool,
+    // where the next read would start if the file is being read sequentially
+    pub read_ahead: u64,
 }
 
 pub struct FileTree<'b> {
@@ -37,6 +100,12 @@ pub struct FileTree<'b> {
     pub parent_map: HashMap<u64, u64>,
     pub current_inode: u64,
 
+    pub handles: HashMap<u64, FileHandle>,
+    pub next_fh: u64,
+
+    // folders and files that aren't on Drive as such, like `.revisions`
+    pub virtual_nodes: VirtualNodes,
+
     pub file_downloader: &'b mut FileDownloader,
     pub conn: rusqlite::Connection,
 }
@@ -59,87 +128,19 @@ impl<'b> FileTree<'b> {
 
         if let Some(new_files) = updates.new_files {
             for fr in new_files {
-                let inode = self.current_inode;
-                self.current_inode += 1;
-
-                let kind = if fr.kind == FileType::Directory {
-                    "directory"
-                } else {
-                    "regular"
-                };
-
-                let mut size = 0;
-                {
-                    let parent_ino = self.conn.query_row_named("SELECT ino FROM files WHERE uuid=:uuid"
-                        , &[( ":uuid", &fr.parent_uuid.clone().as_bytes().to_vec() )]
-                        , |row| -> u64 {
-                            row.get::<i32, i64>(0) as u64
-                        }
-                    ).unwrap();
-                    let parent = try!(self.inode_map.get(&parent_ino).ok_or(DriveError {
-                        kind: DriveErrorType::NoSuchInode,
-                        response: None,
-                    }));
-
-                    match self.file_downloader.retreive_file(&fr.uuid, &parent.id) {
-                        Ok(s) => {
-                            size = s;
-                        },
-                        Err(error) => {
-                            println!("error when saving or downloading file: {:?}", error);
-                            println!("deleting metadata, and trying a fresh save");
-                            try!(
-                                (match error.response {
-                                    Some(ref resp) => {
-                                        self.file_downloader.resolve_error(resp)
-                                    },
-                                    None => {
-                                        println!("no response in error: {:?}", error.kind);
-                                        Err(error)
-                                    }
-                                })
-                                .and(self.file_downloader.retreive_file(&fr.uuid, &parent.id))
-                                .or_else(|err| -> Result<u64, DriveError> {
-                                    println!("error resolution failed. err2: {:?}", err);
-                                    Ok(0)
-                                })
-                            );
-                        }
-                    }
-                }
-
-                self.conn.execute("INSERT INTO files (ino, uuid, parent_ino, name, size, kind)
-                                   VALUES ($1, $2, $3, $4, $5, $6)",
-                                   &[ &(inode as i64),
-                                      &fr.uuid.clone().as_bytes().to_vec(),
-                                      &(parent_inode as i64),
-                                      &fr.name.clone(),
-                                      &(size as i64),
-                                      &kind,
-                                    ]
-                ).unwrap_or_else(|_| {
-//                    println!("file already in filetree db: {}, err: {:?}", fr.name, err);
-//                    println!("updating file information");
-
-                    self.conn.execute("UPDATE files
-                                       SET name=$1
-                                       WHERE uuid=$3"
-                        , &[ &fr.name.clone()
-                           , &fr.uuid.clone().as_bytes().to_vec()
-                           ]
-                    ).unwrap_or_else(|err| {
-                        println!("couldn't update file in filetree db, err: {:?}", err);
-                        0
-                    });
-                    0
-                });
-
-
+                try!(self.add_file(fr, parent_inode));
             }
         }
 
         if let Some(del_files) = updates.deleted_files {
             for fr in del_files {
+                self.conn.execute("DELETE FROM links
+                                   WHERE ino=(SELECT ino FROM files WHERE uuid=:uuid)"
+                    , &[ &fr.uuid.clone().as_bytes().to_vec() ]
+                ).unwrap_or_else(|err| {
+                    println!("couldn't delete links to file: {}, err: {:?}", fr.name, err);
+                    0
+                });
                 self.conn.execute("DELETE FROM files WHERE uuid=:uuid"
                     , &[ &fr.uuid.clone().as_bytes().to_vec() ]
                 ).unwrap_or_else(|err| {
@@ -153,6 +154,210 @@ impl<'b> FileTree<'b> {
 
     }
 
+    /// Adds a file from a listing of the folder at `parent_inode` to the filetree db, along
+    /// with its local copy. It only shows up in the tree once it's loaded by `load_file`.
+    pub fn add_file(&mut self, fr: FileResponse, parent_inode: u64) -> Result<(), DriveError> {
+        let existing_parent = self.conn.query_row_named("SELECT ino, parent_ino FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &fr.uuid.clone().as_bytes().to_vec() )]
+            , |row| -> (u64, u64) {
+                (row.get::<i32, i64>(0) as u64, row.get::<i32, i64>(1) as u64)
+            }
+        ).ok();
+        if let Some((ino, first_parent)) = existing_parent {
+            if first_parent != parent_inode {
+                // the file is already in the tree under another of its parents, so
+                // this parent only gets a hard link to the same inode
+                self.conn.execute("INSERT OR REPLACE INTO links (ino, parent_ino, name)
+                                   VALUES ($1, $2, $3)"
+                    , &[ &(ino as i64)
+                       , &(parent_inode as i64)
+                       , &fr.name.clone()
+                       ]
+                ).unwrap_or_else(|err| {
+                    println!("couldn't link file: {}, err: {:?}", fr.name, err);
+                    0
+                });
+                return Ok(())
+            }
+        }
+
+        // a file already in the db keeps its inode
+        let inode = match existing_parent {
+            Some((ino, _)) => ino,
+            None => {
+                let inode = self.current_inode;
+                self.current_inode += 1;
+                inode
+            }
+        };
+
+        let kind = if fr.kind == FileType::Directory {
+            "directory"
+        } else if fr.kind == FileType::Symlink {
+            "symlink"
+        } else {
+            "regular"
+        };
+
+        let mut size = 0;
+        {
+            let parent_ino = self.conn.query_row_named("SELECT ino FROM files WHERE uuid=:uuid"
+                , &[( ":uuid", &fr.parent_uuid.clone().as_bytes().to_vec() )]
+                , |row| -> u64 {
+                    row.get::<i32, i64>(0) as u64
+                }
+            ).unwrap();
+            let parent = try!(self.inode_map.get(&parent_ino).ok_or(DriveError {
+                kind: DriveErrorType::NoSuchInode,
+                response: None,
+            }));
+
+            match self.file_downloader.retreive_file(&fr.uuid, &parent.id) {
+                Ok(s) => {
+                    size = s;
+                },
+                Err(error) => {
+                    println!("error when saving or downloading file: {:?}", error);
+                    println!("deleting metadata, and trying a fresh save");
+                    try!(
+                        (match error.response {
+                            Some(ref resp) => {
+                                self.file_downloader.resolve_error(resp)
+                            },
+                            None => {
+                                println!("no response in error: {:?}", error.kind);
+                                Err(error)
+                            }
+                        })
+                        .and(self.file_downloader.retreive_file(&fr.uuid, &parent.id))
+                        .or_else(|err| -> Result<u64, DriveError> {
+                            println!("error resolution failed. err2: {:?}", err);
+                            Ok(0)
+                        })
+                    );
+                }
+            }
+        }
+
+        let perm = fr.posix.mode.map(|perm| perm as i64);
+        let uid = fr.posix.uid.map(|uid| uid as i64);
+        let gid = fr.posix.gid.map(|gid| gid as i64);
+        // an mtime set through the mount wins over the time Drive saw the change
+        let mtime = fr.posix.mtime.or(fr.times.modified).map(posix::format_timespec);
+        let ctime = fr.times.modified.map(posix::format_timespec);
+        let crtime = fr.times.created.map(posix::format_timespec);
+        let atime = fr.times.viewed.or(fr.times.modified).map(posix::format_timespec);
+        self.conn.execute("INSERT INTO files (ino, uuid, parent_ino, name, size, kind, perm, uid, gid,
+                                              mtime, ctime, crtime, atime)
+                           VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+                           &[ &(inode as i64),
+                              &fr.uuid.clone().as_bytes().to_vec(),
+                              &(parent_inode as i64),
+                              &fr.name.clone(),
+                              &(size as i64),
+                              &kind,
+                              &perm,
+                              &uid,
+                              &gid,
+                              &mtime,
+                              &ctime,
+                              &crtime,
+                              &atime,
+                            ]
+        ).unwrap_or_else(|_| {
+//            println!("file already in filetree db: {}, err: {:?}", fr.name, err);
+//            println!("updating file information");
+
+            self.conn.execute("UPDATE files
+                               SET name=$1, perm=$2, uid=$3, gid=$4, mtime=$5, ctime=$6, crtime=$7, atime=$8
+                               WHERE uuid=$9"
+                , &[ &fr.name.clone()
+                   , &perm
+                   , &uid
+                   , &gid
+                   , &mtime
+                   , &ctime
+                   , &crtime
+                   , &atime
+                   , &fr.uuid.clone().as_bytes().to_vec()
+                   ]
+            ).unwrap_or_else(|err| {
+                println!("couldn't update file in filetree db, err: {:?}", err);
+                0
+            });
+            0
+        });
+
+        Ok(())
+    }
+
+    /// Moves a file that's already in the tree into `new_parent`, keeping its inode, and
+    /// unloads it along with everything below it, for `load_file` to load it again there.
+    /// Files that aren't in the tree are left to `add_file`.
+    pub fn reparent(&mut self, uuid: &Uuid, new_parent: u64) -> Result<(), DriveError> {
+        let ino = match self.conn.query_row_named("SELECT ino FROM files WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> u64 { row.get::<i32, i64>(0) as u64 }
+        ) {
+            Ok(ino) => ino,
+            Err(_) => return Ok(()),
+        };
+
+        try!(self.conn.execute("DELETE FROM links WHERE ino=$1", &[ &(ino as i64) ]));
+        try!(self.conn.execute("UPDATE files SET parent_ino=$1 WHERE ino=$2"
+            , &[ &(new_parent as i64), &(ino as i64) ]
+        ));
+        self.unload(ino);
+        Ok(())
+    }
+
+    // drops a file from the maps, and whatever is below it. The filetree db is left as
+    // it is, so it can be loaded again
+    fn unload(&mut self, ino: u64) {
+        for children in self.child_map.values_mut() {
+            children.retain(|child| *child != ino);
+        }
+        if let Some(children) = self.child_map.remove(&ino) {
+            for child in children {
+                if self.parent_map.get(&child) == Some(&ino) {
+                    self.unload(child);
+                }
+            }
+        }
+        self.inode_map.remove(&ino);
+        self.parent_map.remove(&ino);
+    }
+
+    /// Loads a file from the filetree db into the folder at `parent_inode`, returning its
+    /// inode.
+    pub fn load_file(&mut self, uuid: &Uuid, parent_inode: u64) -> Result<u64, DriveError> {
+        // paths are relative to the root of the mount, so the root's children get no prefix
+        let parent_path = if self.parent_map.get(&parent_inode) == Some(&parent_inode) {
+            PathBuf::new()
+        } else {
+            try!(self.inode_map.get(&parent_inode).ok_or(DriveError {
+                kind: DriveErrorType::NoSuchInode,
+                response: None,
+            })).path.clone()
+        };
+
+        let fd = try!(self.conn.query_row_named("SELECT uuid, ino, name, kind, size, perm, uid, gid,
+                                                        mtime, ctime, crtime, atime,
+                                                        (SELECT COUNT(*) FROM links WHERE links.ino=files.ino)
+                                                 FROM files
+                                                 WHERE uuid=:uuid"
+            , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+            , |row| -> FileData { file_data(row, &parent_path, parent_inode) }
+        ));
+
+        let ino = fd.attr.ino;
+        self.inode_map.insert(ino, fd);
+        self.child_map.entry(ino).or_insert(Vec::new());
+        self.parent_map.insert(ino, parent_inode);
+        self.child_map.entry(parent_inode).or_insert(Vec::new()).push(ino);
+        Ok(ino)
+    }
+
     pub fn get_files(&mut self, parent_folder_path: &Path, parent_folder_id: &uuid::Uuid, parent_inode: u64) -> Result<(), DriveError> {
         try!(self.check_for_new_files(parent_folder_id, parent_inode));
         println!("Populating FUSE fs...
@@ -164,48 +369,22 @@ impl<'b> FileTree<'b> {
 
     fn _get_files(&mut self, parent_folder_path: &Path, parent_folder_id: &uuid::Uuid, parent_inode: u64) -> Result<(), DriveError> {
         let files = {
-            let mut stmt = try!(self.conn.prepare("SELECT uuid, ino, name, kind, size FROM files
-                                                   WHERE parent_ino=:parent_ino"));
+            // children are either in the folder they were first found in, or hard links to a
+            // file that was found in another folder first
+            let mut stmt = try!(self.conn.prepare("SELECT uuid, ino, name, kind, size, perm, uid, gid,
+                                                          mtime, ctime, crtime, atime,
+                                                          (SELECT COUNT(*) FROM links WHERE links.ino=files.ino)
+                                                   FROM files
+                                                   WHERE parent_ino=:parent_ino
+                                                   UNION ALL
+                                                   SELECT files.uuid, files.ino, links.name, files.kind, files.size,
+                                                          files.perm, files.uid, files.gid,
+                                                          files.mtime, files.ctime, files.crtime, files.atime,
+                                                          (SELECT COUNT(*) FROM links AS l WHERE l.ino=files.ino)
+                                                   FROM links JOIN files ON files.ino=links.ino
+                                                   WHERE links.parent_ino=:parent_ino"));
             let rows = try!(stmt.query_map_named(&[(":parent_ino", &(parent_inode as i64))]
-                , |row| -> FileData {
-                    let uuid = Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).unwrap();
-                    let ino  = row.get::<i32, i64>(1) as u64;
-                    let name = row.get::<i32, String>(2);
-                    let str_kind = row.get::<i32, String>(3);
-                    let size = row.get::<i32, i64>(4) as u64;
-                    let ts = time::now().to_timespec();
-
-                    let mut fadsf = FileType::RegularFile;
-                    if str_kind == "directory" {
-                        fadsf = FileType::Directory;
-                    }
-
-                    let mut new_path = parent_folder_path.to_owned();
-                    new_path.push(name.clone());
-
-                    FileData {
-                        id: uuid,
-                        path: new_path,
-                        parent_inode: parent_inode,
-                        attr: FileAttr {
-                            ino: ino,
-                            size: size,
-                            blocks: size/512,
-                            atime: ts,
-                            mtime: ts,
-                            ctime: ts,
-                            crtime: ts,
-                            kind: fadsf,
-                            perm: 0o777,
-                            nlink: 0,
-                            uid: 1000,
-                            gid: 1000,
-                            rdev: 0,
-                            flags: 0,
-                        },
-                        source_data: SourceData::CreatedFile,
-                    }
-                }
+                , |row| -> FileData { file_data(row, parent_folder_path, parent_inode) }
             ));
 
             let mut files = Vec::new();
@@ -218,6 +397,8 @@ impl<'b> FileTree<'b> {
         for fd in files {
             //println!("found parent {}, adding new child {:?}, inode: {}", parent_folder_id, fd.path, fd.attr.ino);
 
+            // a hard linked folder only needs its children read once
+            let seen = self.inode_map.contains_key(&fd.attr.ino);
             self.inode_map.entry(fd.attr.ino).or_insert(fd.clone());
             self.child_map.entry(fd.attr.ino).or_insert(Vec::new());
             self.parent_map.entry(fd.attr.ino).or_insert(parent_inode);
@@ -226,13 +407,653 @@ impl<'b> FileTree<'b> {
 
             // then recurse to retrieve children files
             if fd.attr.kind == FileType::Directory
-            && fd.attr.ino  != 1 {
+            && fd.attr.ino  != 1
+            && !seen {
                 try!(self._get_files(&fd.path, &fd.id, fd.attr.ino));
             }
         }
 
         Ok(())
     }
+
+    /// Where the symlink at `ino` points, relative to the folder it's in. A shortcut to a
+    /// file that isn't in the mount points to the target's Drive ID, and so dangles.
+    pub fn resolve_link(&self, ino: u64) -> Result<PathBuf, DriveError> {
+        let link = try!(self.inode_map.get(&ino).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: None,
+        }));
+
+        match try!(self.file_downloader.link_target(&link.id)) {
+            LinkTarget::Shortcut { target_id, target_uuid } => {
+                let target = target_uuid.and_then(|uuid| {
+                    self.conn.query_row_named("SELECT ino FROM files WHERE uuid=:uuid"
+                        , &[( ":uuid", &uuid.clone().as_bytes().to_vec() )]
+                        , |row| -> u64 { row.get::<i32, i64>(0) as u64 }
+                    ).ok()
+                }).and_then(|target_ino| self.inode_map.get(&target_ino));
+
+                match target {
+                    Some(target) => {
+                        // paths in the tree are relative to the root of the mount, so climb
+                        // up to it from the folder the link is in
+                        let mut path = PathBuf::new();
+                        for _ in 0..link.path.parent().map(|dir| dir.components().count()).unwrap_or(0) {
+                            path.push("..");
+                        }
+                        path.push(&target.path);
+                        Ok(path)
+                    },
+                    None => Ok(PathBuf::from(target_id)),
+                }
+            },
+            LinkTarget::Path(path) => Ok(PathBuf::from(path)),
+        }
+    }
+
+    pub fn create_symlink(&mut self, parent_inode: u64, name: &Path, target: &Path) -> Result<FileAttr, DriveError> {
+        let parent_uuid = try!(self.inode_map.get(&parent_inode).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: None,
+        })).id.clone();
+        let uuid = try!(self.file_downloader.create_symlink(&parent_uuid, name, target));
+
+        self.add_created_file(parent_inode, name, uuid, FileType::Symlink, target.as_os_str().len() as u64)
+    }
+
+    pub fn file_uuid(&self, ino: u64) -> Result<Uuid, DriveError> {
+        self.inode_map.get(&ino).map(|fd| fd.id.clone()).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: None,
+        })
+    }
+
+    /// The inode of the child of `parent_inode` called `name`, if there is one.
+    pub fn child_by_name(&self, parent_inode: u64, name: &Path) -> Option<u64> {
+        self.child_map.get(&parent_inode).and_then(|children| {
+            children.iter().cloned().find(|child| {
+                self.inode_map.get(child)
+                    .and_then(|fd| fd.path.file_name())
+                    .map(|child_name| child_name == name.as_os_str())
+                    .unwrap_or(false)
+            })
+        })
+    }
+
+    /// Makes a new, empty regular file.
+    pub fn create_file(&mut self, parent_inode: u64, name: &Path) -> Result<FileAttr, DriveError> {
+        let parent_uuid = try!(self.inode_map.get(&parent_inode).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: None,
+        })).id.clone();
+        let uuid = try!(self.file_downloader.create_local_file(&parent_uuid, name));
+
+        self.add_created_file(parent_inode, name, uuid, FileType::RegularFile, 0)
+    }
+
+    // adds a file made through the mount to the tree
+    fn add_created_file(&mut self, parent_inode: u64, name: &Path, uuid: Uuid, kind: FileType, size: u64) -> Result<FileAttr, DriveError> {
+        let name_str = try!(name.to_str().ok_or(DriveError {
+            kind: DriveErrorType::NoFileName,
+            response: None,
+        })).to_string();
+
+        let inode = self.current_inode;
+        self.current_inode += 1;
+        let ts = time::now().to_timespec();
+
+        let fd = FileData {
+            id: uuid,
+            parent_inode: parent_inode,
+            path: self.child_path(parent_inode, name),
+            attr: FileAttr {
+                ino: inode,
+                size: size,
+                blocks: 0,
+                atime: ts,
+                mtime: ts,
+                ctime: ts,
+                crtime: ts,
+                kind: kind,
+                perm: 0o777,
+                nlink: 1,
+                uid: 1000,
+                gid: 1000,
+                rdev: 0,
+                flags: 0,
+            },
+            source_data: SourceData::CreatedFile,
+        };
+
+        let ts_str = posix::format_timespec(ts);
+        try!(self.conn.execute("INSERT INTO files (ino, uuid, parent_ino, name, size, kind, mtime, ctime, crtime, atime)
+                                VALUES ($1, $2, $3, $4, $5, $6, $7, $7, $7, $7)"
+            , &[ &(inode as i64),
+                 &uuid.clone().as_bytes().to_vec(),
+                 &(parent_inode as i64),
+                 &name_str,
+                 &(size as i64),
+                 &(if kind == FileType::Symlink { "symlink" } else { "regular" }),
+                 &ts_str,
+               ]
+        ));
+
+        self.inode_map.entry(inode).or_insert(fd.clone());
+        self.child_map.entry(inode).or_insert(Vec::new());
+        self.parent_map.entry(inode).or_insert(parent_inode);
+        self.child_map.entry(parent_inode).or_insert(Vec::new())
+            .push(inode);
+
+        Ok(fd.attr)
+    }
+
+    /// Changes the mode, owners or times of a file, as asked by a setattr. Everything but
+    /// the atime is also saved on Drive, so it survives a remount.
+    pub fn set_attrs(&mut self, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>,
+                     atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<FileAttr, DriveError> {
+        let (uuid, attr) = {
+            let fd = try!(self.inode_map.get_mut(&ino).ok_or(DriveError {
+                kind: DriveErrorType::NoSuchInode,
+                response: None,
+            }));
+            if let Some(mode) = mode {
+                fd.attr.perm = (mode & 0o7777) as u16;
+            }
+            if let Some(uid) = uid {
+                fd.attr.uid = uid;
+            }
+            if let Some(gid) = gid {
+                fd.attr.gid = gid;
+            }
+            if let Some(atime) = atime {
+                fd.attr.atime = atime;
+            }
+            if let Some(mtime) = mtime {
+                fd.attr.mtime = mtime;
+            }
+            if mode.is_some() || uid.is_some() || gid.is_some() {
+                fd.attr.ctime = time::now().to_timespec();
+            }
+            (fd.id.clone(), fd.attr)
+        };
+
+        if mode.is_none() && uid.is_none() && gid.is_none() && mtime.is_none() {
+            return Ok(attr)
+        }
+
+        try!(self.conn.execute("UPDATE files
+                                SET perm=$1, uid=$2, gid=$3, mtime=$4
+                                WHERE ino=$5"
+            , &[ &(attr.perm as i64)
+               , &(attr.uid as i64)
+               , &(attr.gid as i64)
+               , &posix::format_timespec(attr.mtime)
+               , &(ino as i64)
+               ]
+        ));
+
+        try!(self.file_downloader.set_posix_attrs(&uuid, &PosixAttrs {
+            mode: Some(attr.perm),
+            uid: Some(attr.uid),
+            gid: Some(attr.gid),
+            mtime: Some(attr.mtime),
+        }));
+
+        Ok(attr)
+    }
+
+    pub fn set_size(&mut self, ino: u64, size: u64) -> Result<(), DriveError> {
+        {
+            let fd = try!(self.inode_map.get_mut(&ino).ok_or(DriveError {
+                kind: DriveErrorType::NoSuchInode,
+                response: None,
+            }));
+            fd.attr.size = size;
+            fd.attr.blocks = size/512;
+        }
+
+        try!(self.conn.execute("UPDATE files SET size=$1 WHERE ino=$2"
+            , &[ &(size as i64)
+               , &(ino as i64)
+               ]
+        ));
+
+        Ok(())
+    }
+
+    /// Fails with PermissionDenied unless `uid` and `gid` may write to a file, going by
+    /// its owner, group or other bits, whichever apply. Read-only files, like exported
+    /// Google files, can't be written to by anyone.
+    pub fn check_write_access(&self, ino: u64, uid: u32, gid: u32) -> Result<(), DriveError> {
+        let fd = try!(self.inode_map.get(&ino).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: None,
+        }));
+        if !may_write(&fd.attr, uid, gid) {
+            return Err(DriveError {
+                kind: DriveErrorType::PermissionDenied,
+                response: None,
+            })
+        }
+
+        Ok(())
+    }
+
+    /// Opens the local copy of a file, returning the fh that the other handle methods
+    /// take. Honors O_TRUNC and O_APPEND. Whether the caller may open it for writing is
+    /// left to `check_write_access`, as a file being created can be written to whatever
+    /// its permissions.
+    pub fn open_handle(&mut self, ino: u64, flags: u32) -> Result<u64, DriveError> {
+        let uuid = try!(self.inode_map.get(&ino).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: None,
+        })).id.clone();
+        let path = try!(self.file_downloader.local_path(&uuid));
+
+        // the kernel only passes O_TRUNC on when it's asked to handle it atomically, which
+        // fuse doesn't; otherwise it comes as a setattr, handled by `truncate`
+        let truncate = opens_for_writing(flags) && flags as i32 & libc::O_TRUNC != 0;
+        let file = try!(open_local(&path, flags));
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, FileHandle {
+            ino: ino,
+            uuid: uuid.clone(),
+            file: file,
+            flags: flags,
+            dirty: truncate,
+            read_ahead: 0,
+        });
+
+        if truncate {
+            // an emptied file is as much a change to upload as a written one
+            try!(self.file_downloader.mark_dirty(&uuid));
+            let ts = time::now().to_timespec();
+            if let Some(fd) = self.inode_map.get_mut(&ino) {
+                fd.attr.size = 0;
+                fd.attr.blocks = 0;
+                fd.attr.mtime = ts;
+                fd.attr.ctime = ts;
+            }
+        }
+
+        Ok(fh)
+    }
+
+    /// Cuts a file down, or extends it with zeros, to `size`. `fh` is the handle it's
+    /// open with, if the kernel gave one, as for ftruncate. Whether the caller may write
+    /// to the file is left to `check_write_access`.
+    pub fn truncate(&mut self, ino: u64, size: u64, fh: Option<u64>) -> Result<(), DriveError> {
+        let (uuid, old_size) = {
+            let fd = try!(self.inode_map.get(&ino).ok_or(DriveError {
+                kind: DriveErrorType::NoSuchInode,
+                response: None,
+            }));
+            (fd.id.clone(), fd.attr.size)
+        };
+        if size > old_size {
+            try!(self.check_space(size - old_size));
+        }
+
+        let through_handle = match fh.and_then(|fh| self.handles.get_mut(&fh)) {
+            Some(handle) if handle.ino == ino && opens_for_writing(handle.flags) => {
+                try!(handle.file.set_len(size));
+                handle.dirty = true;
+                true
+            },
+            _ => false,
+        };
+        if !through_handle {
+            let path = try!(self.file_downloader.local_path(&uuid));
+            let file = try!(OpenOptions::new().write(true).open(&path));
+            try!(file.set_len(size));
+        }
+
+        let ts = time::now().to_timespec();
+        if let Some(fd) = self.inode_map.get_mut(&ino) {
+            fd.attr.size = size;
+            fd.attr.blocks = size/512;
+            fd.attr.mtime = ts;
+            fd.attr.ctime = ts;
+        }
+        try!(self.save_attrs(ino));
+        self.file_downloader.mark_dirty(&uuid)
+    }
+
+    fn get_handle(&mut self, fh: u64) -> Result<&mut FileHandle, DriveError> {
+        self.handles.get_mut(&fh).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchFileHandle,
+            response: None,
+        })
+    }
+
+    /// Reads up to `size` bytes at `offset`, less only at the end of the file. Reads go
+    /// straight to the local copy with pread, so they leave the handle's position, which
+    /// writes use, alone.
+    pub fn read_handle(&mut self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, DriveError> {
+        let handle = try!(self.get_handle(fh));
+        let fd = handle.file.as_raw_fd();
+
+        // a read picking up where the last one stopped is likely part of a sequential
+        // read, so the kernel is asked to start on the pieces after it
+        if offset == handle.read_ahead && offset > 0 {
+            unsafe {
+                libc::posix_fadvise(fd, (offset + size as u64) as libc::off_t
+                                   , READ_AHEAD_WINDOW as libc::off_t, libc::POSIX_FADV_WILLNEED);
+            }
+        }
+
+        let data = try!(read_at(&handle.file, offset, size));
+        handle.read_ahead = offset + data.len() as u64;
+
+        Ok(data)
+    }
+
+    pub fn write_handle(&mut self, fh: u64, offset: u64, data: &[u8]) -> Result<u32, DriveError> {
+        let growth = {
+            let handle = try!(self.get_handle(fh));
+            let size = try!(handle.file.metadata()).len();
+            let append = handle.flags as i32 & libc::O_APPEND != 0;
+            let end = if append { size } else { offset } + data.len() as u64;
+            if end > size { end - size } else { 0 }
+        };
+        if growth > 0 {
+            try!(self.check_space(growth));
+        }
+
+        let (ino, uuid, first_write, end) = {
+            let handle = try!(self.get_handle(fh));
+            let end = try!(write_at(&mut handle.file, offset, data));
+
+            let first_write = !handle.dirty;
+            handle.dirty = true;
+            (handle.ino, handle.uuid.clone(), first_write, end)
+        };
+
+        // the file has to count as dirty from its first write on, so it isn't replaced
+        // by its copy on Drive in the meantime. Later writes only hold its upload off
+        if first_write {
+            try!(self.file_downloader.mark_dirty(&uuid));
+        } else {
+            try!(self.file_downloader.delay_upload(&uuid));
+        }
+
+        let ts = time::now().to_timespec();
+        if let Some(fd) = self.inode_map.get_mut(&ino) {
+            fd.attr.mtime = ts;
+            fd.attr.ctime = ts;
+            if end > fd.attr.size {
+                fd.attr.size = end;
+                fd.attr.blocks = end/512;
+            }
+        }
+
+        Ok(data.len() as u32)
+    }
+
+    /// Fails with NoSpace or QuotaExceeded when `bytes` more wouldn't fit on the local disk,
+    /// or on Drive. Files written but not uploaded yet aren't counted against the quota
+    /// until they are, so this only catches writes that go over it on their own.
+    pub fn check_space(&mut self, bytes: u64) -> Result<(), DriveError> {
+        let space = try!(self.file_downloader.storage_space());
+        if bytes > space.local_free {
+            return Err(DriveError {
+                kind: DriveErrorType::NoSpace,
+                response: None,
+            })
+        }
+        if let Some(limit) = space.quota_limit {
+            if space.quota_usage + bytes > limit {
+                return Err(DriveError {
+                    kind: DriveErrorType::QuotaExceeded,
+                    response: None,
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Saves what was written through a handle, and queues it for upload.
+    pub fn flush_handle(&mut self, fh: u64) -> Result<(), DriveError> {
+        let (ino, uuid) = {
+            let handle = try!(self.get_handle(fh));
+            if !handle.dirty {
+                return Ok(())
+            }
+            try!(handle.file.flush());
+            handle.dirty = false;
+            (handle.ino, handle.uuid.clone())
+        };
+
+        try!(self.save_attrs(ino));
+        // restarts the quiet period, so the upload waits for the writes to settle
+        self.file_downloader.mark_dirty(&uuid)
+    }
+
+    pub fn release_handle(&mut self, fh: u64) -> Result<(), DriveError> {
+        let result = self.flush_handle(fh);
+        // dropping the handle closes its file
+        self.handles.remove(&fh);
+        result
+    }
+
+    // writes the size and times a file got while it was open to the filetree db
+    fn save_attrs(&mut self, ino: u64) -> Result<(), DriveError> {
+        let attr = try!(self.inode_map.get(&ino).ok_or(DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: None,
+        })).attr;
+
+        try!(self.conn.execute("UPDATE files SET size=$1, mtime=$2, ctime=$3 WHERE ino=$4"
+            , &[ &(attr.size as i64)
+               , &posix::format_timespec(attr.mtime)
+               , &posix::format_timespec(attr.ctime)
+               , &(ino as i64)
+               ]
+        ));
+
+        Ok(())
+    }
+
+    /// The path in the tree of a new child of `parent_inode`. Paths are relative to the
+    /// root of the mount, so the root's own children get no prefix.
+    pub fn child_path(&self, parent_inode: u64, name: &Path) -> PathBuf {
+        let is_root = self.parent_map.get(&parent_inode) == Some(&parent_inode);
+        match self.inode_map.get(&parent_inode) {
+            Some(parent) if !is_root => parent.path.join(name),
+            _ => name.to_owned(),
+        }
+    }
+}
+
+// a file as the filetree db has it, in the folder at `parent_folder_path`
+fn file_data(row: &rusqlite::Row, parent_folder_path: &Path, parent_inode: u64) -> FileData {
+    let uuid = Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).unwrap();
+    let ino  = row.get::<i32, i64>(1) as u64;
+    let name = row.get::<i32, String>(2);
+    let str_kind = row.get::<i32, String>(3);
+    let size = row.get::<i32, i64>(4) as u64;
+    let perm = row.get::<i32, Option<i64>>(5).map(|perm| perm as u16).unwrap_or(0o777);
+    let uid = row.get::<i32, Option<i64>>(6).map(|uid| uid as u32).unwrap_or(1000);
+    let gid = row.get::<i32, Option<i64>>(7).map(|gid| gid as u32).unwrap_or(1000);
+    let nlink = row.get::<i32, i64>(12) as u32 + 1;
+    // files from before timestamps were kept show up as just changed
+    let ts = time::now().to_timespec();
+    let get_time = |i: i32| row.get::<i32, Option<String>>(i)
+                               .and_then(|t| posix::parse_timespec(&t));
+    let mtime  = get_time(8).unwrap_or(ts);
+    let ctime  = get_time(9).unwrap_or(mtime);
+    let crtime = get_time(10).unwrap_or(ctime);
+    let atime  = get_time(11).unwrap_or(mtime);
+
+    let mut fadsf = FileType::RegularFile;
+    if str_kind == "directory" {
+        fadsf = FileType::Directory;
+    } else if str_kind == "symlink" {
+        fadsf = FileType::Symlink;
+    }
+
+    let mut new_path = parent_folder_path.to_owned();
+    new_path.push(name.clone());
+
+    FileData {
+        id: uuid,
+        path: new_path,
+        parent_inode: parent_inode,
+        attr: FileAttr {
+            ino: ino,
+            size: size,
+            blocks: size/512,
+            atime: atime,
+            mtime: mtime,
+            ctime: ctime,
+            crtime: crtime,
+            kind: fadsf,
+            perm: perm,
+            nlink: nlink,
+            uid: uid,
+            gid: gid,
+            rdev: 0,
+            flags: 0,
+        },
+        source_data: SourceData::CreatedFile,
+    }
+}
+
+/// Brings a filetree db made by an older version up to date.
+pub fn update_db_schema(conn: &rusqlite::Connection) {
+    add_column(conn, "files", "perm INTEGER");
+    add_column(conn, "files", "uid INTEGER");
+    add_column(conn, "files", "gid INTEGER");
+    add_column(conn, "files", "mtime TEXT");
+    add_column(conn, "files", "ctime TEXT");
+    add_column(conn, "files", "crtime TEXT");
+    add_column(conn, "files", "atime TEXT");
+    conn.execute("CREATE TABLE IF NOT EXISTS links (
+                      ino        INTEGER NOT NULL,
+                      parent_ino INTEGER NOT NULL,
+                      name       TEXT NOT NULL,
+                      PRIMARY KEY (ino, parent_ino)
+                  )", &[]
+    ).expect("couldn't create links table in filetree db");
+}
+
+/// Adds a column to a table of an older db that was made without it. Fails silently when
+/// the column is already there.
+pub fn add_column(conn: &rusqlite::Connection, table: &str, column: &str) {
+    conn.execute(&format!("ALTER TABLE {} ADD COLUMN {}", table, column), &[])
+        .unwrap_or(0);
+}
+
+/// Finds the file at a path relative to the root of the mount, following the names kept
+/// in the filetree db, so it works while nothing is mounted.
+pub fn lookup_path(conn: &rusqlite::Connection, path: &Path) -> Result<Uuid, DriveError> {
+    let mut ino: i64 = 1;
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy().into_owned(),
+            Component::CurDir | Component::RootDir => continue,
+            _ => return Err(DriveError {
+                kind: DriveErrorType::NoSuchInode,
+                response: Some(format!("can't look up {:?}", path)),
+            }),
+        };
+        ino = try!(conn.query_row_named("SELECT ino FROM files
+                                         WHERE parent_ino=:parent_ino AND name=:name AND ino!=parent_ino
+                                         UNION
+                                         SELECT ino FROM links
+                                         WHERE parent_ino=:parent_ino AND name=:name"
+            , &[(":parent_ino", &ino), (":name", &name)]
+            , |row| -> i64 { row.get(0) }
+        ).map_err(|_| DriveError {
+            kind: DriveErrorType::NoSuchInode,
+            response: Some(format!("no such file: {:?}", path)),
+        }));
+    }
+
+    let uuid = try!(conn.query_row_named("SELECT uuid FROM files WHERE ino=:ino"
+        , &[(":ino", &ino)]
+        , |row| -> Vec<u8> { row.get(0) }
+    ));
+    Uuid::from_bytes(&uuid).map_err(|_| DriveError {
+        kind: DriveErrorType::FailedUuidLookup,
+        response: None,
+    })
+}
+
+/// Whether the owner, group or other write bit that applies to `uid` and `gid` is set.
+/// Only the caller's primary group is known, so its other groups don't count.
+pub fn may_write(attr: &FileAttr, uid: u32, gid: u32) -> bool {
+    let bit = if uid == attr.uid {
+        0o200
+    } else if gid == attr.gid {
+        0o020
+    } else {
+        0o002
+    };
+    attr.perm & bit != 0
+}
+
+/// Whether open `flags` ask for write access.
+pub fn opens_for_writing(flags: u32) -> bool {
+    let access = flags as i32 & libc::O_ACCMODE;
+    access == libc::O_WRONLY || access == libc::O_RDWR
+}
+
+/// Opens the local copy of a file with the access mode, O_TRUNC and O_APPEND of `flags`.
+pub fn open_local(path: &Path, flags: u32) -> io::Result<File> {
+    let flags_i = flags as i32;
+    let access = flags_i & libc::O_ACCMODE;
+    let write = opens_for_writing(flags);
+
+    OpenOptions::new()
+        .read(access == libc::O_RDONLY || access == libc::O_RDWR)
+        .write(write)
+        .append(write && flags_i & libc::O_APPEND != 0)
+        .truncate(write && flags_i & libc::O_TRUNC != 0)
+        .open(path)
+}
+
+/// Writes all of `data` at `offset`, returning where the write ended. A file opened with
+/// O_APPEND writes to its end whatever the offset, so the end is only known from where
+/// the write left the file.
+pub fn write_at(file: &mut File, offset: u64, data: &[u8]) -> io::Result<u64> {
+    try!(file.seek(SeekFrom::Start(offset)));
+    try!(file.write_all(data));
+    file.seek(SeekFrom::Current(0))
+}
+
+/// Reads up to `size` bytes of `file` at `offset` with pread, stopping short only at the
+/// end of the file.
+pub fn read_at(file: &File, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    let fd = file.as_raw_fd();
+    let mut data = vec![0u8; size as usize];
+    let mut read = 0;
+    while read < data.len() {
+        let n = unsafe {
+            libc::pread(fd
+                       , data[read..].as_mut_ptr() as *mut libc::c_void
+                       , (data.len() - read) as libc::size_t
+                       , (offset + read as u64) as libc::off_t)
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue
+            }
+            return Err(err)
+        }
+        if n == 0 {
+            // end of file
+            break
+        }
+        read += n as usize;
+    }
+    data.truncate(read);
+
+    Ok(data)
 }
 
 pub fn get_file_checksum(file_path: &Path) -> Result<String, DriveError> {
@@ -246,3 +1067,131 @@ pub fn get_file_checksum(file_path: &Path) -> Result<String, DriveError> {
 
     Ok(md5.result_str())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use super::*;
+
+    // a file of its own for each test, as they run in parallel
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("driver-test-{}-{}", unsafe { libc::getpid() }, name));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(contents).unwrap();
+        path
+    }
+
+    fn attr(perm: u16) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: Timespec::new(0, 0),
+            mtime: Timespec::new(0, 0),
+            ctime: Timespec::new(0, 0),
+            crtime: Timespec::new(0, 0),
+            kind: FileType::RegularFile,
+            perm: perm,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn write_access_goes_by_the_bits_that_apply() {
+        // owner
+        assert!(may_write(&attr(0o600), 1000, 100));
+        assert!(!may_write(&attr(0o060), 1000, 100));
+        // group
+        assert!(may_write(&attr(0o460), 1001, 100));
+        assert!(!may_write(&attr(0o604), 1001, 100));
+        // other
+        assert!(may_write(&attr(0o446), 1001, 101));
+        assert!(!may_write(&attr(0o664), 1001, 101));
+        // exported Google files
+        assert!(!may_write(&attr(0o444), 1000, 100));
+    }
+
+    #[test]
+    fn write_flags() {
+        assert!(!opens_for_writing(libc::O_RDONLY as u32));
+        assert!(opens_for_writing(libc::O_WRONLY as u32));
+        assert!(opens_for_writing((libc::O_RDWR | libc::O_APPEND) as u32));
+    }
+
+    #[test]
+    fn o_trunc_empties_the_file() {
+        let path = temp_file("open-trunc", b"0123456789");
+        let file = open_local(&path, (libc::O_RDWR | libc::O_TRUNC) as u32).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn o_trunc_is_ignored_for_reads() {
+        let path = temp_file("open-trunc-rdonly", b"0123456789");
+        let file = open_local(&path, (libc::O_RDONLY | libc::O_TRUNC) as u32).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 10);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn o_append_writes_at_the_end() {
+        let path = temp_file("open-append", b"0123456789");
+        let mut file = open_local(&path, (libc::O_WRONLY | libc::O_APPEND) as u32).unwrap();
+        assert_eq!(write_at(&mut file, 2, b"ab").unwrap(), 12);
+        let mut contents = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"0123456789ab");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_go_to_the_offset() {
+        let path = temp_file("write-offset", b"0123456789");
+        let mut file = open_local(&path, libc::O_RDWR as u32).unwrap();
+        assert_eq!(write_at(&mut file, 2, b"ab").unwrap(), 4);
+        assert_eq!(write_at(&mut file, 12, b"cd").unwrap(), 14);
+        assert_eq!(read_at(&file, 0, 20).unwrap(), b"01ab456789\0\0cd");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_stop_at_the_end_of_the_file() {
+        let path = temp_file("read-eof", b"0123456789");
+        let file = File::open(&path).unwrap();
+        assert_eq!(read_at(&file, 0, 4).unwrap(), b"0123");
+        assert_eq!(read_at(&file, 6, 10).unwrap(), b"6789");
+        assert_eq!(read_at(&file, 10, 4).unwrap(), b"");
+        assert_eq!(read_at(&file, 100, 4).unwrap(), b"");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_of_empty_files() {
+        let path = temp_file("read-empty", b"");
+        let file = File::open(&path).unwrap();
+        assert_eq!(read_at(&file, 0, 4096).unwrap(), b"");
+        assert_eq!(read_at(&file, 0, 0).unwrap(), b"");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_leave_the_position_alone() {
+        let path = temp_file("read-position", b"0123456789");
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        assert_eq!(read_at(&file, 5, 2).unwrap(), b"56");
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"23456789");
+        fs::remove_file(&path).unwrap();
+    }
+}
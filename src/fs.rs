@@ -3,23 +3,32 @@
 extern crate uuid;
 
 use std::path::Path;
-use libc::{ENOENT, ENOSYS};
+use libc::{ENOENT, ENOSYS, EIO, EBADF, EACCES, EEXIST, ENOSPC, EDQUOT, ENODATA, EPERM, EINVAL, EAGAIN, EROFS, ERANGE, O_EXCL};
 use time;
 use time::Timespec;
-use fuse::{FileAttr, Filesystem, Request, ReplyAttr, ReplyEntry, ReplyDirectory, ReplyData, ReplyOpen, ReplyEmpty, ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap};
+use fuse::{Filesystem, Request, ReplyAttr, ReplyEntry, ReplyDirectory, ReplyData, ReplyOpen, ReplyEmpty, ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyXattr};
+use fuse::consts::FOPEN_DIRECT_IO;
 use std::ffi::OsStr;
 
-use std::fs::{File};
-use std::os::unix::io::{IntoRawFd, FromRawFd};
-use uuid::Uuid;
+use std::os::unix::ffi::OsStrExt;
 
 use filetree::*;
 use types::*;
+use vnode::VirtualNodes;
+
+const STATFS_BLOCK_SIZE: u32 = 4096;
+const STATFS_NAME_LEN: u32 = 255;
+const STATFS_FREE_FILES: u64 = 1 << 32;
 
 impl<'b> Filesystem for FileTree<'b> {
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         println!("getattr(ino={})", ino);
 
+        if let Some(entry) = self.virtual_nodes.get(ino) {
+            let ttl = time::now().to_timespec();
+            reply.attr(&ttl, &entry.attr);
+            return
+        }
         if let Some(path) = self.inode_map.get(&ino) {
             let ttl = time::now().to_timespec();
             reply.attr(&ttl, &path.attr);
@@ -31,7 +40,19 @@ impl<'b> Filesystem for FileTree<'b> {
         }
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = Path::new(name);
+        if self.is_virtual_lookup(parent, name) {
+            match self.lookup_virtual(parent, name) {
+                Ok(attr) => {
+                    let ttl = time::now().to_timespec();
+                    reply.entry(&ttl, &attr, 0)
+                },
+                Err(err) => reply.error(handle_errno(&err)),
+            }
+            return
+        }
+
         if let Some(children) = self.child_map.get(&parent) {
             for i in children {
                 if let Some(child) = self.inode_map.get(&i) {
@@ -48,86 +69,115 @@ impl<'b> Filesystem for FileTree<'b> {
         reply.error(ENOENT)
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectory) {
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
         println!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
-        if offset == 0 {
-            reply.add(ino, 0, FileType::Directory, &Path::new("."));
-            reply.add(*self.parent_map.get(&ino).expect(&format!("no parent inode for {}", ino)), 1, FileType::Directory, &Path::new(".."));
-
-            let mut new_offest = 1;
-            if let Some(children) = self.child_map.get(&ino) {
-                new_offest += 1;
-
-                for child_inode in children {
-                    if let Some(child) = self.inode_map.get(&child_inode) {
-                        reply.add(*child_inode, new_offest, child.attr.kind, &child.path.file_name().expect(&format!("no file_name {:?}", child)));
-                    } else {
-                        println!("no inode for child {:?}, parent {:?}", child_inode, children);
-                        panic!()
-                    }
+        if VirtualNodes::is_virtual(ino) {
+            let (parent, entries) = match self.read_virtual_dir(ino) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    println!("couldn't list {}, err: {:?}", ino, err);
+                    reply.error(handle_errno(&err));
+                    return
                 }
-
-                reply.ok();
+            };
+
+            // each entry's offset is where the next readdir picks up after it
+            let mut all = vec![(ino, FileType::Directory, Path::new(".").to_owned())
+                              , (parent, FileType::Directory, Path::new("..").to_owned())];
+            all.extend(entries);
+            for (i, &(child_ino, kind, ref name)) in all.iter().enumerate().skip(offset as usize) {
+                if reply.add(child_ino, i as i64 + 1, kind, name) {
+                    break
+                }
+            }
+            reply.ok();
+            return
+        }
+        // the trash and the views are listed at the root, unlike the other virtual folders
+        let virtual_dirs = if ino == 1 { self.root_virtual_dirs() } else { Vec::new() };
+        let children = match self.child_map.get(&ino) {
+            Some(children) => children,
+            None => {
+                reply.error(ENOENT);
                 return
+            }
+        };
+
+        // as for virtual folders, each entry's offset is where the next readdir picks up
+        let mut all = vec![(ino, FileType::Directory, Path::new(".").to_owned())
+                          , (*self.parent_map.get(&ino).expect(&format!("no parent inode for {}", ino)), FileType::Directory, Path::new("..").to_owned())];
+        for child_inode in children {
+            if let Some(child) = self.inode_map.get(&child_inode) {
+                all.push((*child_inode, child.attr.kind, Path::new(child.path.file_name().expect(&format!("no file_name {:?}", child))).to_owned()));
             } else {
-                println!("fdasd");
+                println!("no inode for child {:?}, parent {:?}", child_inode, children);
+                panic!()
             }
         }
-
-        reply.error(ENOENT);
+        all.extend(virtual_dirs);
+        for (i, &(child_ino, kind, ref name)) in all.iter().enumerate().skip(offset as usize) {
+            if reply.add(child_ino, i as i64 + 1, kind, name) {
+                break
+            }
+        }
+        reply.ok();
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
         println!("read(ino={}, fh={}, offset={}, size={})", ino, fh, offset, size);
 
-        if let Some(fd) = self.inode_map.get(&ino) {
-            match self.file_downloader.read_file(&fd.id) {
-                Ok(data) => {
-                    let offset = if offset >= data.len() as u64 {
-                        println!("offset change: {} -> {}", offset, data.len());
-                        (data.len() - 1) as u64
-                    } else {
-                        offset
-                    };
-
-                    let real_size: usize = if size as u64 + offset >= data.len() as u64 {
-                        (data.len() - offset as usize) as usize
-                    } else {
-                        size as usize
-                    };
-                    let d  = data[offset as usize..(offset + real_size as u64) as usize].to_vec();
-
-                    println!("{}", d.len());
-
-                    reply.data(&d);
-                    return
-                },
-                Err(err) => {
-                    println!("err when reading file, id: {}: {:?}", ino, err)
-                }
+        match self.read_handle(fh, offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => {
+                println!("err when reading file, id: {}: {:?}", ino, err);
+                reply.error(handle_errno(&err))
             }
-        } else {
-            println!("no inode found in map, {}", ino);
         }
-
-        reply.error(ENOENT);
     }
 
-    // implement open flags with file handle later
-    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         println!("open(ino={})", ino);
+        if VirtualNodes::is_virtual(ino) {
+            match self.open_virtual(ino, flags) {
+                Ok((fh, true)) => reply.opened(fh, flags | FOPEN_DIRECT_IO),
+                Ok((fh, false)) => reply.opened(fh, flags),
+                Err(err) => {
+                    println!("couldn't open {}, err: {:?}", ino, err);
+                    reply.error(handle_errno(&err))
+                }
+            }
+            return
+        }
 
-        let uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE ino=:ino"
-            , &[( ":ino", &(ino as i64) )]
-            , |row| -> Uuid {
-                Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).unwrap()
+        let uuid = match self.inode_map.get(&ino) {
+            Some(fd) => fd.id.clone(),
+            None => {
+                reply.error(ENOENT);
+                return
             }
-        ).unwrap();
-        match self.file_downloader.verify_checksum(&uuid, None) {
-            Ok(_) => reply.opened(0, flags),
+        };
+        if opens_for_writing(flags) {
+            if let Err(err) = self.check_write_access(ino, req.uid(), req.gid()) {
+                reply.error(handle_errno(&err));
+                return
+            }
+        }
+        match self.file_downloader.validate_file(&uuid) {
+            Ok(Some(size)) => {
+                self.set_size(ino, size).unwrap_or_else(|err| {
+                    println!("couldn't update size of {}, err: {:?}", ino, err);
+                });
+            },
+            Ok(None) => { },
+            // being offline shouldn't keep the local copy from being opened
+            Err(err) => println!("couldn't check {} against Drive, opening the local copy, err: {:?}", ino, err),
+        }
+
+        match self.open_handle(ino, flags) {
+            Ok(fh) => reply.opened(fh, flags),
             Err(err) => {
-                println!("err in validating checksum of local file, err: {:?}", err);
-                reply.error(0)
+                println!("couldn't open {}, err: {:?}", ino, err);
+                reply.error(handle_errno(&err))
             }
         }
     }
@@ -135,193 +185,371 @@ impl<'b> Filesystem for FileTree<'b> {
     fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {
         println!("forget(ino={})", _ino);
     }
-    fn setattr(&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
-        println!("setattr(ino={}, mode={:?}, uid={:?})", _ino, _mode, _uid);
-        if let Some(path) = self.inode_map.get(&_ino) {
-            let ts = time::now().to_timespec();
-            reply.attr(&ts, &path.attr);
+    fn setattr(&mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<Timespec>, mtime: Option<Timespec>, fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        println!("setattr(ino={}, mode={:?}, uid={:?}, gid={:?}, size={:?})", ino, mode, uid, gid, size);
+        if VirtualNodes::is_virtual(ino) {
+            reply.error(EROFS);
             return
         }
 
-        reply.error(ENOENT)
+        if let Some(size) = size {
+            // a file open for writing can be cut down through its handle, whoever may
+            // write to it now
+            let writable_handle = fh.and_then(|fh| self.handles.get(&fh))
+                .map(|handle| handle.ino == ino && opens_for_writing(handle.flags))
+                .unwrap_or(false);
+            let checked = if writable_handle {
+                Ok(())
+            } else {
+                self.check_write_access(ino, req.uid(), req.gid())
+            };
+            if let Err(err) = checked.and_then(|_| self.truncate(ino, size, fh)) {
+                println!("couldn't truncate {}, err: {:?}", ino, err);
+                reply.error(handle_errno(&err));
+                return
+            }
+        }
+
+        match self.set_attrs(ino, mode, uid, gid, atime, mtime) {
+            Ok(attr) => {
+                let ts = time::now().to_timespec();
+                reply.attr(&ts, &attr);
+            },
+            Err(DriveError { kind: DriveErrorType::NoSuchInode, .. }) => reply.error(ENOENT),
+            Err(err) => {
+                println!("couldn't set attributes of {}, err: {:?}", ino, err);
+                reply.error(EIO)
+            }
+        }
+    }
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        println!("readlink(ino={})", ino);
+
+        match self.resolve_link(ino) {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(err) => {
+                println!("couldn't resolve link {}, err: {:?}", ino, err);
+                reply.error(ENOENT)
+            }
+        }
     }
-    fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) { unimplemented!() }
-    fn mknod(&mut self, _req: &Request, _parent: u64, _name: &Path, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+    fn mknod(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
         println!("mknod(name={:?}, parent={}, mode={})", _name, _parent, _mode);
         reply.error(ENOENT)
     }
-    fn mkdir(&mut self, _req: &Request, _parent: u64, _name: &Path, _mode: u32, reply: ReplyEntry) { unimplemented!() }
-    fn unlink(&mut self, _req: &Request, _parent: u64, _name: &Path, reply: ReplyEmpty) { unimplemented!() }
-    fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &Path, reply: ReplyEmpty) { unimplemented!() }
-    fn symlink(&mut self, _req: &Request, _parent: u64, _name: &Path, _link: &Path, reply: ReplyEntry) { unimplemented!() }
-    fn rename(&mut self, _req: &Request, _parent: u64, _name: &Path, _newparent: u64, _newname: &Path, reply: ReplyEmpty) { unimplemented!() }
-    fn link(&mut self, _req: &Request, _ino: u64, _newparent: u64, _newname: &Path, reply: ReplyEntry) { unimplemented!() }
-    fn write(&mut self, _req: &Request, _ino: u64, _fh: u64, _offset: u64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
-        println!("write(ino={}, fh={}, offset={}, len={})", _ino, _fh, _offset, _data.len());
-
-        let uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE ino=:ino"
-            , &[( ":ino", &(_ino as i64) )]
-            , |row| -> Uuid {
-                Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).unwrap()
+    fn mkdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) { unimplemented!() }
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = Path::new(name);
+        println!("unlink(parent={}, name={:?})", parent, name);
+        self.remove_entry(parent, name, reply)
+    }
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = Path::new(name);
+        println!("rmdir(parent={}, name={:?})", parent, name);
+        self.remove_entry(parent, name, reply)
+    }
+    fn symlink(&mut self, _req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let name = Path::new(name);
+        println!("symlink(parent={}, name={:?}, link={:?})", parent, name, link);
+        if VirtualNodes::is_virtual(parent) {
+            reply.error(EROFS);
+            return
+        }
+
+        match self.create_symlink(parent, name, link) {
+            Ok(attr) => {
+                let ttl = time::now().to_timespec();
+                reply.entry(&ttl, &attr, 0)
+            },
+            Err(err) => {
+                println!("couldn't create symlink {:?}, err: {:?}", name, err);
+                reply.error(EIO)
             }
-        ).unwrap();
+        }
+    }
+    fn rename(&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
+        let (name, newname) = (Path::new(name), Path::new(newname));
+        println!("rename(parent={}, name={:?}, newparent={}, newname={:?})", parent, name, newparent, newname);
 
-        reply.written(self.file_downloader.write_file(&uuid, _data, _offset).unwrap())
+        // so far the only moves are out of the trash
+        if !VirtualNodes::is_virtual(parent) && !VirtualNodes::is_virtual(newparent) {
+            reply.error(ENOSYS);
+            return
+        }
+        match self.rename_virtual(parent, name, newparent, newname) {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                println!("couldn't move {:?} to {:?}, err: {:?}", name, newname, err);
+                reply.error(handle_errno(&err))
+            }
+        }
     }
-    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        println!("flush(ino={}, fh={})", _ino, _fh);
+    fn link(&mut self, _req: &Request, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) { unimplemented!() }
+    fn write(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        println!("write(ino={}, fh={}, offset={}, len={})", ino, fh, offset, data.len());
 
-        let uuid = self.conn.query_row_named("SELECT uuid FROM files WHERE ino=:ino"
-            , &[( ":ino", &(_ino as i64) )]
-            , |row| -> Uuid {
-                Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).unwrap()
+        match self.write_handle(fh, offset as u64, data) {
+            Ok(written) => reply.written(written),
+            Err(err) => {
+                println!("err when writing file, id: {}: {:?}", ino, err);
+                reply.error(handle_errno(&err))
             }
-        ).unwrap();
+        }
+    }
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        println!("flush(ino={}, fh={})", ino, fh);
 
-        match self.file_downloader.flush_file(&uuid) {
+        match self.flush_handle(fh) {
             Ok(_) => reply.ok(),
             Err(err) => {
-                println!("error while flushing {}, {:?}", _ino, err);
-                reply.error(-1);
+                println!("error while queueing {} for upload, {:?}", ino, err);
+                reply.error(handle_errno(&err))
             }
         }
     }
-    fn release(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
-        println!("release(ino={}, fh={}, flush={:?})", _ino, _fh, _flush);
+    fn release(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        println!("release(ino={}, fh={}, flush={:?})", ino, fh, _flush);
 
-        unsafe {
-            let _: File = FromRawFd::from_raw_fd(_fh as i32);
-            // when fd goes out of scope, it will close the file?
+        match self.release_handle(fh) {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                println!("error while releasing {}, {:?}", ino, err);
+                reply.error(handle_errno(&err))
+            }
         }
-
-        reply.ok();
     }
     fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) { unimplemented!() }
     fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
         println!("opendir(ino={})", ino);
 
-        if ino == 1 {
-            match File::open("random asas place") {
-                Ok(handle) => {
-                    let h = handle.into_raw_fd();
-                    reply.opened(h as u64, _flags);
-                    return
-                }
-                Err(_) => { }
-            }
-        }
-
-        if let Some(fd) = self.inode_map.get(&ino) {
-            match File::open(&fd.path) {
-                Ok(handle) => {
-                    let h = handle.into_raw_fd();
-                    reply.opened(h as u64, _flags);
-                    return
-                }
-                Err(error) => {
-                    //println!("no downloaded folder for {}, err: {:?}", fd.path.to_string_lossy(), error);
-                }
-            }
+        // readdir works from the tree alone, so folders need no handle of their own
+        if self.inode_map.contains_key(&ino) || self.virtual_nodes.get(ino).is_some() {
+            reply.opened(0, _flags)
         } else {
-            println!("no inode found in map, {}", ino);
+            reply.error(ENOENT)
         }
-
-//         reply.error(ENOENT);
-        reply.opened(0, _flags)
     }
 
     fn releasedir(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
         println!("releasedir(ino={}, fh={})", _ino, _fh);
-
-        unsafe {
-            let _: File = FromRawFd::from_raw_fd(_fh as i32);
-            // when fd goes out of scope, it will close the file?
-        }
-
         reply.ok()
     }
     fn fsyncdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) { unimplemented!() }
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         println!("statfs(ino={})", _ino);
-        reply.error(ENOENT)
+
+        match self.file_downloader.storage_space() {
+            Ok(space) => {
+                // an unlimited Drive is only as big as the disk its files are copied to
+                let (total, free) = match space.quota_limit {
+                    Some(limit) => (limit, limit.saturating_sub(space.quota_usage)),
+                    None => (space.local_total, space.local_free),
+                };
+                // Drive has no limit on the number of files, so there's always room for more
+                let files = self.inode_map.len() as u64;
+                reply.statfs(total / STATFS_BLOCK_SIZE as u64
+                            , free / STATFS_BLOCK_SIZE as u64
+                            , free / STATFS_BLOCK_SIZE as u64
+                            , files + STATFS_FREE_FILES
+                            , STATFS_FREE_FILES
+                            , STATFS_BLOCK_SIZE
+                            , STATFS_NAME_LEN
+                            , STATFS_BLOCK_SIZE)
+            },
+            Err(err) => {
+                println!("couldn't get free space, err: {:?}", err);
+                reply.error(EIO)
+            }
+        }
+    }
+    fn setxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+        println!("setxattr(ino={}, name={:?})", ino, name);
+
+        let result = self.file_uuid(ino).and_then(|uuid| {
+            let name = try!(xattr_name(name));
+            self.file_downloader.set_xattr(&uuid, name, value)
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                println!("couldn't set {:?} on {}, err: {:?}", name, ino, err);
+                reply.error(handle_errno(&err))
+            }
+        }
+    }
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        println!("getxattr(ino={}, name={:?}, size={})", ino, name, size);
+
+        let result = self.file_uuid(ino).and_then(|uuid| {
+            let name = try!(xattr_name(name));
+            self.file_downloader.get_xattr(&uuid, name)
+        });
+        match result {
+            Ok(value) => reply_xattr(&value, size, reply),
+            Err(err) => reply.error(handle_errno(&err)),
+        }
+    }
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        println!("listxattr(ino={}, size={})", ino, size);
+        // virtual files and folders aren't on Drive, so they have no attributes
+        if self.virtual_nodes.get(ino).is_some() {
+            reply_xattr(&[], size, reply);
+            return
+        }
+
+        let result = self.file_uuid(ino).and_then(|uuid| {
+            self.file_downloader.list_xattrs(&uuid)
+        });
+        match result {
+            Ok(names) => reply_xattr(&xattr_list(&names), size, reply),
+            Err(err) => {
+                println!("couldn't list attributes of {}, err: {:?}", ino, err);
+                reply.error(handle_errno(&err))
+            }
+        }
     }
-    fn setxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) { unimplemented!() }
-    fn getxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyData) {
-        println!("getxattr(ino={}, name={:?})", _ino, _name);
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        println!("removexattr(ino={}, name={:?})", ino, name);
+
+        let result = self.file_uuid(ino).and_then(|uuid| {
+            let name = try!(xattr_name(name));
+            self.file_downloader.remove_xattr(&uuid, name)
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                println!("couldn't remove {:?} from {}, err: {:?}", name, ino, err);
+                reply.error(handle_errno(&err))
+            }
+        }
     }
-    fn listxattr(&mut self, _req: &Request, _ino: u64, reply: ReplyEmpty) { unimplemented!() }
-    fn removexattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) { unimplemented!() }
     fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
         println!("access(ino={})", _ino);
-        if self.inode_map.contains_key(&_ino) {
+        if self.inode_map.contains_key(&_ino) || self.virtual_nodes.get(_ino).is_some() {
             reply.ok()
         } else {
             reply.error(ENOENT)
         }
     }
-    fn create(&mut self, _req: &Request, parent_inode: u64, _name: &Path, _mode: u32, _flags: u32, reply: ReplyCreate) {
-        println!("create(name{:?}, parent={}, mode={}, flags={})", _name, parent_inode, _mode, _flags);
+    fn create(&mut self, _req: &Request, parent_inode: u64, name: &OsStr, _mode: u32, flags: u32, reply: ReplyCreate) {
+        let name = Path::new(name);
+        println!("create(name{:?}, parent={}, mode={}, flags={})", name, parent_inode, _mode, flags);
+        if VirtualNodes::is_virtual(parent_inode) {
+            reply.error(EROFS);
+            return
+        }
 
-        let inode = self.conn.query_row("SELECT MAX(ino) FROM files"
-            , &[], |row| -> u64 {
-                row.get::<i32, i64>(0) as u64
-            }
-        ).unwrap() + 1;
-        let (parent_uuid, parent_path) = self.conn.query_row_named("SELECT uuid, path FROM files WHERE parent_ino=:parent_ino"
-            , &[ (":parent_ino", &(parent_inode as i64)) ]
-            , |row| -> (Uuid, String) {
-                ( Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).unwrap()
-                , row.get(1)
-                )
+        if flags as i32 & O_EXCL != 0 && self.child_by_name(parent_inode, name).is_some() {
+            reply.error(EEXIST);
+            return
+        }
+        // even an empty file needs a little room, and a Drive that's already full won't
+        // take it
+        if let Err(err) = self.check_space(1) {
+            println!("no room for {:?}, err: {:?}", name, err);
+            reply.error(handle_errno(&err));
+            return
+        }
+
+        let attr = match self.create_file(parent_inode, name) {
+            Ok(attr) => attr,
+            Err(err) => {
+                println!("couldn't create {:?}, err: {:?}", name, err);
+                reply.error(EIO);
+                return
             }
-        ).unwrap();
-
-        let ts = time::now().to_timespec();
-        let uuid = self.file_downloader.create_local_file(&parent_uuid, _name).unwrap();
-
-        let path = Path::new(&(parent_path + &_name.to_str().unwrap())).to_owned();
-        let fd = FileData {
-            id: uuid,
-            parent_inode: parent_inode,
-            path: path,
-            attr: FileAttr {
-                ino: inode,
-                size: 0,
-                blocks: 0,
-                atime: ts,
-                mtime: ts,
-                ctime: ts,
-                crtime: ts,
-                kind: FileType::RegularFile,
-                perm: 0o777,
-                nlink: 0,
-                uid: 1000,
-                gid: 1000,
-                rdev: 0,
-                flags: 0,
-            },
-            source_data: SourceData::CreatedFile,
         };
 
-        self.child_map.entry(parent_inode).or_insert(Vec::new())
-            .push(inode);
-        self.inode_map.entry(inode).or_insert(fd.clone());
-        self.child_map.entry(inode).or_insert(Vec::new());
-        self.parent_map.entry(inode).or_insert(parent_inode);
-
-        self.conn.execute("INSERT INTO files (ino, uuid, parent_ino, name, size, kind)
-                           VALUES ($1, $2, $3, $4, $5, $6)"
-           , &[ &(inode as i64),
-                &uuid.clone().as_bytes().to_vec(),
-                &(parent_inode as i64),
-                &(_name.to_str().expect("nilfalsdfs")),
-                &(0 as i64),
-              ]
-        ).unwrap();
-
-        reply.created(&ts, &fd.attr, fd.attr.ino, 0, 0)
+        match self.open_handle(attr.ino, flags) {
+            Ok(fh) => {
+                let ts = time::now().to_timespec();
+                reply.created(&ts, &attr, 0, fh, flags)
+            },
+            Err(err) => {
+                println!("couldn't open {:?}, err: {:?}", name, err);
+                reply.error(handle_errno(&err))
+            }
+        }
     }
     fn getlk(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, reply: ReplyLock) { unimplemented!() }
     fn setlk(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: u32, _pid: u32, _sleep: bool, reply: ReplyEmpty) { unimplemented!() }
     fn bmap(&mut self, _req: &Request, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) { unimplemented!() }
 }
+
+impl<'b> FileTree<'b> {
+    // unlink and rmdir, which so far only delete things from the trash
+    fn remove_entry(&mut self, parent: u64, name: &Path, reply: ReplyEmpty) {
+        if !VirtualNodes::is_virtual(parent) {
+            reply.error(ENOSYS);
+            return
+        }
+        match self.remove_virtual(parent, name) {
+            Ok(_) => reply.ok(),
+            Err(err) => {
+                println!("couldn't remove {:?}, err: {:?}", name, err);
+                reply.error(handle_errno(&err))
+            }
+        }
+    }
+}
+
+// the errno a failed operation is reported to the kernel with
+fn handle_errno(err: &DriveError) -> i32 {
+    match err.kind {
+        DriveErrorType::NoSuchInode => ENOENT,
+        DriveErrorType::NoSuchFileHandle => EBADF,
+        DriveErrorType::PermissionDenied => EACCES,
+        DriveErrorType::NoSpace => ENOSPC,
+        DriveErrorType::QuotaExceeded => EDQUOT,
+        DriveErrorType::NoSuchXattr => ENODATA,
+        DriveErrorType::ReadOnlyXattr => EPERM,
+        DriveErrorType::InvalidXattrValue => EINVAL,
+        DriveErrorType::NotYetUploaded => EAGAIN,
+        DriveErrorType::ReadOnly => EROFS,
+        DriveErrorType::FileExists => EEXIST,
+        DriveErrorType::InvalidQuery => EINVAL,
+        _ => EIO,
+    }
+}
+
+// a caller asking with a size of 0 only wants to know how big a buffer to pass, and one
+// that's too small gets ERANGE
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32)
+    } else if value.len() > size as usize {
+        reply.error(ERANGE)
+    } else {
+        reply.data(value)
+    }
+}
+
+// listxattr's reply: the names, each followed by a nul
+fn xattr_list(names: &[String]) -> Vec<u8> {
+    let mut list = Vec::new();
+    for name in names {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+    }
+    list
+}
+
+// attribute names that aren't utf8 can't be any of ours
+fn xattr_name(name: &OsStr) -> Result<&str, DriveError> {
+    name.to_str().ok_or(DriveError {
+        kind: DriveErrorType::NoSuchXattr,
+        response: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xattr_names_are_nul_terminated() {
+        assert_eq!(xattr_list(&["user.drive.id".to_string(), "user.origin".to_string()])
+                  , b"user.drive.id\0user.origin\0".to_vec());
+        assert_eq!(xattr_list(&[]), Vec::<u8>::new());
+    }
+}
@@ -1,4 +1,4 @@
-extern crate hyper;
+#[macro_use] extern crate hyper;
 extern crate rustc_serialize;
 extern crate fuse;
 extern crate libc;
@@ -14,3 +14,10 @@ pub mod types;
 pub mod fs;
 pub mod filetree;
 pub mod drive;
+pub mod config;
+pub mod upload;
+pub mod conflict;
+pub mod export;
+pub mod posix;
+pub mod xattr;
+pub mod vnode;
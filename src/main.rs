@@ -11,26 +11,308 @@ extern crate time;
 extern crate rusqlite;
 
 use std::collections::hash_map::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::thread;
 use uuid::Uuid;
 use fuse::FileAttr;
 
 use driver::types::*;
 use driver::filetree::*;
 use driver::drive::*;
+use driver::config::MountConfig;
+use driver::upload::*;
+use driver::conflict::*;
+use driver::vnode::VirtualNodes;
+use driver::posix::format_timespec;
+
+const ROOT_FOLDER_NAME: &'static str = ".drive_files2";
+const ROOT_FOLDER_ID: &'static str = "0B7TtU3YsiIjTeW1vTGc0a1Y1MFE";
+const MOUNT_POINT: &'static str = "root.2";
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(|arg| arg.as_str()) {
+        Some("status") => print_status(),
+        Some("share") => share(&args[1..]),
+        Some("unshare") => unshare(&args[1..]),
+        Some("restore") => restore(&args[1..]),
+        Some("trash") => trash(&args[1..]),
+        Some("search") => search(&args[1..]),
+        _ => {
+            let mut config = MountConfig::default();
+            let mut args = args.iter();
+            while let Some(arg) = args.next() {
+                if arg == "-o" {
+                    let options = args.next().expect("-o needs a comma separated list of options");
+                    config.parse_options(options).expect("invalid mount option");
+                }
+            }
+
+            mount(config)
+        }
+    }
+}
+
+fn print_status() {
+    let conn = rusqlite::Connection::open("drive.db").unwrap();
+    let uploads = pending_uploads(&conn).expect("couldn't read upload queue");
+    let now = time::get_time().sec;
+
+    println!("{} pending upload(s)", uploads.len());
+    let rejected = uploads.iter()
+        .filter(|upload| upload.over_quota())
+        .count();
+    if rejected > 0 {
+        println!("{} upload(s) rejected by Drive for going over the storage quota", rejected);
+    }
+    for upload in uploads {
+        let over_quota = upload.over_quota();
+        let path = upload.path.unwrap_or(upload.uuid.to_string());
+        let wait = if upload.next_attempt > now { upload.next_attempt - now } else { 0 };
+        match upload.last_error {
+            Some(_) if over_quota => {
+                println!("  {} (rejected, Drive is out of space; attempts: {}, retry in {}s)", path, upload.attempts, wait)
+            },
+            Some(err) => println!("  {} (attempts: {}, retry in {}s, last error: {})", path, upload.attempts, wait, err),
+            None      => println!("  {} (uploading in {}s)", path, wait),
+        }
+    }
+
+    let conflicts = recent_conflicts(&conn, 10).expect("couldn't read conflict log");
+    if !conflicts.is_empty() {
+        println!("recent conflicts:");
+    }
+    for conflict in conflicts {
+        let path = conflict.path.unwrap_or(conflict.uuid.to_string());
+        let detected = time::at(time::Timespec::new(conflict.detected_at, 0));
+        match conflict.conflict_name {
+            Some(name) => println!("  {} at {}: local copy saved as {}", path, detected.rfc822(), name),
+            None       => println!("  {} at {}: kept {} version", path, detected.rfc822(), conflict.policy),
+        }
+    }
+}
+
+// driver share <path> --with <email|domain|anyone> [--role reader|commenter|writer]
+// driver share --list <path>
+fn share(args: &[String]) {
+    let usage = "usage: driver share <path> --with <email|domain|anyone> [--role <role>], or driver share --list <path>";
+    let mut path = None;
+    let mut with = None;
+    let mut role = "reader".to_string();
+    let mut list = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--with" => with = Some(flag_value(&mut args, usage)),
+            "--role" => role = flag_value(&mut args, usage),
+            "--list" => list = true,
+            _ => path = Some(path_arg(arg, usage)),
+        }
+    }
+    let path = path.expect(usage);
+
+    let fd = command_downloader();
+    let fid = drive_id_for_path(&fd, &path);
+    if list {
+        for permission in fd.list_permissions(&fid).expect("couldn't list permissions") {
+            let who = permission.emailAddress.or(permission.domain).unwrap_or(permission.kind);
+            match permission.displayName {
+                Some(name) => println!("{}\t{} ({})", permission.role, who, name),
+                None       => println!("{}\t{}", permission.role, who),
+            }
+        }
+    } else {
+        let with = with.expect(usage);
+        let permission = fd.share(&fid, &with, &role).expect("couldn't share file");
+        println!("shared {} with {} as {}", path, with, permission.role);
+    }
+}
+
+// driver unshare <path> --with <email|domain|anyone>
+fn unshare(args: &[String]) {
+    let mut path = None;
+    let mut with = None;
+    let usage = "usage: driver unshare <path> --with <email|domain|anyone>";
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--with" => with = Some(flag_value(&mut args, usage)),
+            _ => path = Some(path_arg(arg, usage)),
+        }
+    }
+    let path = path.expect(usage);
+    let with = with.expect(usage);
+
+    let fd = command_downloader();
+    let fid = drive_id_for_path(&fd, &path);
+    match fd.unshare(&fid, &with).expect("couldn't unshare file") {
+        0 => println!("{} isn't shared with {}", path, with),
+        _ => println!("stopped sharing {} with {}", path, with),
+    }
+}
+
+// driver restore <path> --revision <revision ID or time>
+// driver restore <path>, to list the revisions there are
+fn restore(args: &[String]) {
+    let mut path = None;
+    let mut revision = None;
+    let usage = "usage: driver restore <path> [--revision <revision>]";
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--revision" => revision = Some(flag_value(&mut args, usage)),
+            _ => path = Some(path_arg(arg, usage)),
+        }
+    }
+    let path = path.expect(usage);
+
+    let fd = command_downloader();
+    let uuid = uuid_for_path(&path);
+    let revisions = fd.list_revisions(&uuid).expect("couldn't list revisions");
+    let revision = match revision {
+        Some(revision) => revision,
+        None => {
+            for revision in revisions {
+                println!("{}\t{}\t{} bytes", revision.file_name(), revision.id, revision.size);
+            }
+            return
+        }
+    };
+
+    // revisions go by the same names as in .revisions, or by their IDs
+    let found = revisions.iter()
+        .find(|candidate| candidate.id == revision || candidate.file_name() == revision)
+        .unwrap_or_else(|| panic!("{} has no revision {}", path, revision));
+    let size = fd.restore_revision(&uuid, &found.id).expect("couldn't restore revision");
+
+    let conn = rusqlite::Connection::open("files.db").unwrap();
+    let now = format_timespec(time::get_time());
+    conn.execute("UPDATE files SET size=$1, mtime=$2, ctime=$2 WHERE uuid=$3"
+        , &[ &(size as i64), &now, &uuid.as_bytes().to_vec() ]
+    ).expect("couldn't update filetree db");
+
+    println!("restored {} to its revision from {} ({} bytes), it will be uploaded as the newest"
+            , path, found.file_name(), size);
+    // the mount only reads sizes from the filetree db as it starts
+    println!("if {} is mounted, remount it to see the restored contents", MOUNT_POINT);
+}
+
+// driver trash, to list what's in the trash
+// driver trash empty
+fn trash(args: &[String]) {
+    let fd = command_downloader();
+    match args.first().map(|arg| arg.as_str()) {
+        Some("empty") => {
+            fd.empty_trash().expect("couldn't empty the trash");
+            println!("emptied the trash");
+        },
+        Some(arg) => println!("usage: driver trash [empty], not {}", arg),
+        None => {
+            for file in fd.list_trash(None).expect("couldn't list the trash") {
+                let kind = if file.kind == FileType::Directory { "folder" } else { "file" };
+                println!("{}\t{}\t{}", kind, file.id, file.name);
+            }
+        }
+    }
+}
+
+// driver search "fullText contains 'invoice'"
+fn search(args: &[String]) {
+    let query = args.join(" ");
+    if query.is_empty() {
+        println!("usage: driver search <query>");
+        return
+    }
+
+    let mut fd = command_downloader();
+    for result in fd.search(&query, None).expect("couldn't search Drive") {
+        // local copies are kept at the same paths as in the mount
+        let path = result.uuid.and_then(|uuid| fd.local_path(&uuid).ok())
+            .and_then(|path| path.strip_prefix(ROOT_FOLDER_NAME).ok().map(|path| Path::new(MOUNT_POINT).join(path)));
+        match path {
+            Some(path) => println!("{}", path.display()),
+            None       => println!("{} ({}, not in the mount)", result.name, result.id),
+        }
+    }
+}
+
+// the value after a flag like --with, which can't be missing or be another flag
+fn flag_value<'a, I: Iterator<Item=&'a String>>(args: &mut I, usage: &str) -> String {
+    match args.next() {
+        Some(value) if !value.starts_with("--") => value.clone(),
+        _ => panic!("{}", usage),
+    }
+}
+
+// anything that isn't one of a subcommand's flags is its path, but a mistyped flag
+// shouldn't be taken for one
+fn path_arg(arg: &str, usage: &str) -> String {
+    if arg.starts_with("--") {
+        panic!("unknown option {}\n{}", arg, usage);
+    }
+    arg.to_string()
+}
+
+// a downloader for subcommands that talk to Drive while the mount may not be running
+fn command_downloader() -> DriveFileDownloader {
+    let conn = rusqlite::Connection::open("files.db").unwrap();
+    let root_folder_uuid = conn.query_row("SELECT uuid FROM files WHERE ino=1", &[]
+        , |row| -> Uuid {
+            Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).expect("invalid uuid for the root folder")
+        }).expect("nothing has been mounted yet");
+
+    DriveFileDownloader::new(
+        root_folder_uuid,
+        ROOT_FOLDER_ID.to_string(),
+        Path::new(ROOT_FOLDER_NAME).to_owned(),
+        rusqlite::Connection::open("drive.db").unwrap(),
+        MountConfig::default()
+    ).expect("failure in reading access file")
+}
+
+// `path` made absolute, with symlinks and `..` in the folders leading to it resolved.
+// The file itself is left alone, as it may be a symlink in the mount
+fn absolute_path(path: &Path) -> PathBuf {
+    let path = std::env::current_dir().unwrap().join(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent.canonicalize()
+            .map(|parent| parent.join(name))
+            .unwrap_or(path.clone()),
+        _ => path.clone(),
+    }
+}
+
+// paths are given as they are under the mount point, from any folder, or relative to it
+fn uuid_for_path(path: &str) -> Uuid {
+    let path = PathBuf::from(path);
+    let mount_point = absolute_path(Path::new(MOUNT_POINT));
+    let absolute = absolute_path(&path);
+    let relative = absolute.strip_prefix(&mount_point)
+        .map(|relative| relative.to_owned())
+        .unwrap_or(path.clone());
+
+    let conn = rusqlite::Connection::open("files.db").unwrap();
+    lookup_path(&conn, &relative).unwrap_or_else(|_| panic!("no such file: {:?}", path))
+}
+
+fn drive_id_for_path(fd: &DriveFileDownloader, path: &str) -> String {
+    fd.drive_id(&uuid_for_path(path)).unwrap_or_else(|_| panic!("{:?} hasn't been uploaded to Drive yet", path))
+}
+
+fn mount(config: MountConfig) {
 //    let root_folder = (vec![], "0B7TtU3YsiIjTTS1oUE5wZFpsYVk");
-    let root_folder_name = ".drive_files2";
+    let root_folder_name = ROOT_FOLDER_NAME;
     let root_folder_path = Path::new(root_folder_name);
 //    let root_folder_id =  "0B7TtU3YsiIjTWjBOM0YwYkVBa1U";
 //    let root_folder_id =  "0B7TtU3YsiIjTaEd3WlVSMGRERlk";
 //    let root_folder_id =  "root";
-    let root_folder_id = "0B7TtU3YsiIjTeW1vTGc0a1Y1MFE";
+    let root_folder_id = ROOT_FOLDER_ID;
     let root_folder_inode = 1;
 //    let root_folder = (vec!["rot".to_string()], "0B7TtU3YsiIjTeHJGR1VKMHB3cWs");
 
     let conn = rusqlite::Connection::open("files.db").unwrap();
+    update_db_schema(&conn);
     let root_folder_uuid = {
         conn.query_row("SELECT uuid FROM files WHERE ino=1", &[]
         , |row| -> Uuid {
@@ -48,14 +330,33 @@ fn main() {
         root_folder_uuid,
         root_folder_id.to_string(),
         Path::new(root_folder_name).to_owned(),
-        rusqlite::Connection::open("drive.db").unwrap()
+        rusqlite::Connection::open("drive.db").unwrap(),
+        config.clone()
     ).expect("failure in reading access file");
 
+    {
+        let root_folder_id = root_folder_id.to_string();
+        let config = config.clone();
+        thread::spawn(move || {
+            let uploader_fd = DriveFileDownloader::new(
+                root_folder_uuid,
+                root_folder_id,
+                Path::new(root_folder_name).to_owned(),
+                rusqlite::Connection::open("drive.db").unwrap(),
+                config.clone()
+            ).expect("failure in reading access file");
+            Uploader::new(uploader_fd, rusqlite::Connection::open("drive.db").unwrap(), config).run()
+        });
+    }
+
     let mut ft = FileTree {
         inode_map: HashMap::new(),
         child_map: HashMap::new(),
         parent_map: HashMap::new(),
         current_inode: last_ino,
+        handles: HashMap::new(),
+        next_fh: 1,
+        virtual_nodes: VirtualNodes::new(config.views.clone()),
         file_downloader: &mut fd,
         conn: conn,
     };
@@ -104,5 +405,7 @@ fn main() {
 //    println!("{:?}\n", ft.inode_map);
 //    println!("{:?}", ft.child_map);
 
-    fuse::mount(ft, &"root.2", &[]);
+    if let Err(err) = fuse::mount(ft, &MOUNT_POINT, &[]) {
+        println!("couldn't mount {}, err: {:?}", MOUNT_POINT, err);
+    }
 }
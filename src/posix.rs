@@ -0,0 +1,183 @@
+use std::collections::hash_map::HashMap;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+
+use libc;
+use rustc_serialize::json::{Json, ToJson};
+use time::Timespec;
+
+use types::*;
+
+// POSIX metadata is kept on Drive as app properties, which only driver reads back
+const MODE_PROPERTY: &'static str  = "driver_mode";
+const UID_PROPERTY: &'static str   = "driver_uid";
+const GID_PROPERTY: &'static str   = "driver_gid";
+const MTIME_PROPERTY: &'static str = "driver_mtime";
+
+/// Restores what was saved by `to_app_properties`. Owners are saved by name, so they're
+/// only restored when the user or group also exists on this machine.
+pub fn from_app_properties(properties: Option<&HashMap<String, String>>) -> PosixAttrs {
+    let properties = match properties {
+        Some(properties) => properties,
+        None => return PosixAttrs::default(),
+    };
+
+    PosixAttrs {
+        mode: properties.get(MODE_PROPERTY)
+                        .and_then(|mode| u16::from_str_radix(mode, 8).ok())
+                        .map(|mode| mode & 0o7777),
+        uid: properties.get(UID_PROPERTY).and_then(|name| user_id(name)),
+        gid: properties.get(GID_PROPERTY).and_then(|name| group_id(name)),
+        mtime: properties.get(MTIME_PROPERTY).and_then(|mtime| parse_timespec(mtime)),
+    }
+}
+
+pub fn to_app_properties(attrs: &PosixAttrs) -> BTreeMap<String, Json> {
+    let mut properties = BTreeMap::new();
+    if let Some(mode) = attrs.mode {
+        properties.insert(MODE_PROPERTY.to_string(), format!("{:o}", mode).to_json());
+    }
+    if let Some(name) = attrs.uid.and_then(user_name) {
+        properties.insert(UID_PROPERTY.to_string(), name.to_json());
+    }
+    if let Some(name) = attrs.gid.and_then(group_name) {
+        properties.insert(GID_PROPERTY.to_string(), name.to_json());
+    }
+    if let Some(mtime) = attrs.mtime {
+        properties.insert(MTIME_PROPERTY.to_string(), format_timespec(mtime).to_json());
+    }
+    properties
+}
+
+/// Formats a time as `<seconds>.<nanoseconds>`, which keeps every bit of an mtime.
+pub fn format_timespec(ts: Timespec) -> String {
+    format!("{}.{:09}", ts.sec, ts.nsec)
+}
+
+pub fn parse_timespec(s: &str) -> Option<Timespec> {
+    let mut parts = s.splitn(2, '.');
+    let sec = match parts.next().and_then(|sec| sec.parse::<i64>().ok()) {
+        Some(sec) => sec,
+        None => return None,
+    };
+    let nsec = match parts.next() {
+        Some(nsec) => match nsec.parse::<i32>() {
+            Ok(nsec) => nsec,
+            Err(_) => return None,
+        },
+        None => 0,
+    };
+
+    Some(Timespec::new(sec, nsec))
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    unsafe {
+        let pw = libc::getpwuid(uid);
+        if pw.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr((*pw).pw_name).to_string_lossy().into_owned())
+        }
+    }
+}
+
+fn user_id(name: &str) -> Option<u32> {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return None,
+    };
+    unsafe {
+        let pw = libc::getpwnam(name.as_ptr());
+        if pw.is_null() { None } else { Some((*pw).pw_uid) }
+    }
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    unsafe {
+        let gr = libc::getgrgid(gid);
+        if gr.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr((*gr).gr_name).to_string_lossy().into_owned())
+        }
+    }
+}
+
+fn group_id(name: &str) -> Option<u32> {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return None,
+    };
+    unsafe {
+        let gr = libc::getgrnam(name.as_ptr());
+        if gr.is_null() { None } else { Some((*gr).gr_gid) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::HashMap;
+    use time::Timespec;
+
+    use types::*;
+    use super::*;
+
+    #[test]
+    fn timespec_round_trip() {
+        for ts in &[Timespec::new(0, 0), Timespec::new(1467000000, 123456789), Timespec::new(-1, 500)] {
+            assert_eq!(parse_timespec(&format_timespec(*ts)), Some(*ts));
+        }
+    }
+
+    #[test]
+    fn timespec_keeps_leading_zeros_of_nanoseconds() {
+        assert_eq!(format_timespec(Timespec::new(5, 1)), "5.000000001");
+        assert_eq!(parse_timespec("5.000000001"), Some(Timespec::new(5, 1)));
+    }
+
+    #[test]
+    fn timespec_without_nanoseconds() {
+        assert_eq!(parse_timespec("42"), Some(Timespec::new(42, 0)));
+    }
+
+    #[test]
+    fn invalid_timespec() {
+        assert_eq!(parse_timespec(""), None);
+        assert_eq!(parse_timespec("soon"), None);
+        assert_eq!(parse_timespec("1.x"), None);
+    }
+
+    #[test]
+    fn app_properties_round_trip() {
+        let attrs = PosixAttrs {
+            mode: Some(0o4755),
+            // root is the one user every machine has
+            uid: Some(0),
+            gid: Some(0),
+            mtime: Some(Timespec::new(1467000000, 5)),
+        };
+        let properties = to_app_properties(&attrs).into_iter()
+            .map(|(key, value)| (key, value.as_string().unwrap().to_string()))
+            .collect::<HashMap<String, String>>();
+
+        let restored = from_app_properties(Some(&properties));
+        assert_eq!(restored.mode, attrs.mode);
+        assert_eq!(restored.uid, attrs.uid);
+        assert_eq!(restored.gid, attrs.gid);
+        assert_eq!(restored.mtime, attrs.mtime);
+    }
+
+    #[test]
+    fn mode_is_kept_to_permission_bits() {
+        let mut properties = HashMap::new();
+        properties.insert("driver_mode".to_string(), "170755".to_string());
+        assert_eq!(from_app_properties(Some(&properties)).mode, Some(0o755));
+    }
+
+    #[test]
+    fn no_app_properties() {
+        let attrs = from_app_properties(None);
+        assert!(attrs.mode.is_none() && attrs.uid.is_none() && attrs.gid.is_none() && attrs.mtime.is_none());
+    }
+}
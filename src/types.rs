@@ -9,9 +9,12 @@ extern crate rusqlite;
 
 use rustc_serialize::json::{Json, ToJson};
 use std::collections::BTreeMap;
+use std::collections::hash_map::HashMap;
 use std::path::{PathBuf};
 pub use fuse::FileType;
 use fuse::FileAttr;
+use time;
+use time::Timespec;
 
 #[derive (RustcDecodable, Debug, Clone)]
 pub struct TokenResponse {
@@ -34,6 +37,8 @@ impl ToJson for TokenResponse {
     }
 }
 
+/// A Drive v3 File resource, with the fields the mount makes use of. Keep it in step with
+/// `DRIVE_FILE_FIELDS`, which is what gets asked for.
 #[derive (RustcDecodable, Debug, Clone)]
 pub struct DriveFileResponse {
     pub kind: String, // essentially unused, will ostensibly always be "drive#file"
@@ -42,6 +47,85 @@ pub struct DriveFileResponse {
     pub mimeType: String,
     pub parents: Vec<String>,
     pub path: Option<PathBuf>,
+    // Google files have no size or checksum, as they have no content of their own
+    pub size: Option<u64>,
+    pub md5Checksum: Option<String>,
+    pub version: Option<String>,
+    pub headRevisionId: Option<String>,
+    pub trashed: Option<bool>,
+    pub owners: Option<Vec<DriveUser>>,
+    pub capabilities: Option<DriveCapabilities>,
+    pub webViewLink: Option<String>,
+    // download links for each format a Google file can be exported as, keyed by MIME type
+    pub exportLinks: Option<HashMap<String, String>>,
+    pub shortcutDetails: Option<ShortcutDetails>,
+    pub appProperties: Option<HashMap<String, String>>,
+    // set by the user, unlike appProperties which are private to driver
+    pub properties: Option<HashMap<String, String>>,
+    pub description: Option<String>,
+    pub starred: Option<bool>,
+    // RFC 3339 timestamps
+    pub modifiedTime: Option<String>,
+    pub createdTime: Option<String>,
+    pub viewedByMeTime: Option<String>,
+}
+
+// fields of the File resource decoded into DriveFileResponse
+const DRIVE_FILE_FIELDS: &'static [&'static str] = &[
+    "kind", "id", "name", "mimeType", "parents",
+    "size", "md5Checksum", "version", "headRevisionId", "trashed",
+    "owners", "capabilities", "webViewLink", "exportLinks",
+    "shortcutDetails", "appProperties", "properties", "description", "starred",
+    "modifiedTime", "createdTime", "viewedByMeTime",
+];
+
+impl DriveFileResponse {
+    /// The `fields` parameter for a request returning a single file, already url encoded.
+    pub fn fields() -> String {
+        DRIVE_FILE_FIELDS.join("%2C")
+    }
+
+    /// The `fields` parameter for a files.list request.
+    pub fn list_fields() -> String {
+        format!("files({})", DriveFileResponse::fields())
+    }
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct DriveUser {
+    pub displayName: Option<String>,
+    pub emailAddress: Option<String>,
+    pub permissionId: Option<String>,
+    // whether this is the user the mount is signed in as
+    pub me: Option<bool>,
+}
+
+// what the signed in user may do with a file
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct DriveCapabilities {
+    pub canEdit: Option<bool>,
+    pub canDownload: Option<bool>,
+    pub canRename: Option<bool>,
+    pub canTrash: Option<bool>,
+    pub canDelete: Option<bool>,
+    pub canShare: Option<bool>,
+    pub canAddChildren: Option<bool>,
+    pub canListChildren: Option<bool>,
+    pub canReadRevisions: Option<bool>,
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct ShortcutDetails {
+    pub targetId: String,
+    pub targetMimeType: Option<String>,
+}
+
+#[derive (Debug, Clone)]
+pub enum LinkTarget {
+    // a Drive shortcut, and the file it points to, if that file is in the mount
+    Shortcut { target_id: String, target_uuid: Option<uuid::Uuid> },
+    // a symlink made through the mount, which points wherever it was told to
+    Path(String),
 }
 
 #[derive (Debug, Clone)]
@@ -56,9 +140,28 @@ pub struct FileResponse {
     pub parent_uuid: uuid::Uuid,
     pub kind: FileType,
     pub name: String,
+    pub posix: PosixAttrs,
+    pub times: FileTimes,
     pub source_data: SourceData,
 }
 
+// when a file was last changed, made and looked at, as far as Drive knows
+#[derive (Debug, Clone, Default)]
+pub struct FileTimes {
+    pub modified: Option<Timespec>,
+    pub created: Option<Timespec>,
+    pub viewed: Option<Timespec>,
+}
+
+// POSIX metadata of a file, for whichever parts aren't just the defaults
+#[derive (Debug, Clone, Default)]
+pub struct PosixAttrs {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mtime: Option<Timespec>,
+}
+
 #[derive (Debug, Clone)]
 pub struct FileData {
     pub id: uuid::Uuid,
@@ -87,10 +190,213 @@ pub struct ErrorDetailsResponse {
     pub message: String,
 }
 
+// what Drive reports back about a file once an upload to it completes
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct UploadedFileResponse {
+    pub id: String,
+    pub version: Option<String>,
+    pub headRevisionId: Option<String>,
+    pub md5Checksum: Option<String>,
+    pub size: Option<u64>,
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct RemoteVersionResponse {
+    pub name: String,
+    pub parents: Vec<String>,
+    pub version: Option<String>,
+    pub headRevisionId: Option<String>,
+}
+
+// who a file is shared with, and how. Decoded by hand, as one of its fields is `type`
+#[derive (Debug, Clone)]
+pub struct PermissionResponse {
+    pub id: String,
+    // user, group, domain or anyone
+    pub kind: String,
+    pub role: String,
+    pub emailAddress: Option<String>,
+    pub domain: Option<String>,
+    pub displayName: Option<String>,
+}
+
+impl PermissionResponse {
+    pub fn from_json(json: &Json) -> Option<PermissionResponse> {
+        let obj = match json.as_object() {
+            Some(obj) => obj,
+            None => return None,
+        };
+        let get = |key: &str| obj.get(key).and_then(|value| value.as_string()).map(|value| value.to_string());
+
+        match (get("id"), get("type"), get("role")) {
+            (Some(id), Some(kind), Some(role)) => Some(PermissionResponse {
+                id: id,
+                kind: kind,
+                role: role,
+                emailAddress: get("emailAddress"),
+                domain: get("domain"),
+                displayName: get("displayName"),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl ToJson for PermissionResponse {
+    fn to_json(&self) -> Json {
+        let mut d = BTreeMap::new();
+        d.insert("id".to_string(),   self.id.to_json());
+        d.insert("type".to_string(), self.kind.to_json());
+        d.insert("role".to_string(), self.role.to_json());
+        if let Some(ref email) = self.emailAddress {
+            d.insert("emailAddress".to_string(), email.to_json());
+        }
+        if let Some(ref domain) = self.domain {
+            d.insert("domain".to_string(), domain.to_json());
+        }
+        if let Some(ref name) = self.displayName {
+            d.insert("displayName".to_string(), name.to_json());
+        }
+        Json::Object(d)
+    }
+}
+
+// one of a file's past versions, as the revisions API lists it
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct RevisionResponse {
+    pub id: String,
+    pub modifiedTime: String,
+    pub size: Option<u64>,
+    pub md5Checksum: Option<String>,
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct RevisionListResponse {
+    pub revisions: Vec<RevisionResponse>,
+}
+
+/// A past version of a file, as it shows up in the file's `.revisions` folder.
+#[derive (Debug, Clone)]
+pub struct Revision {
+    pub id: String,
+    pub modified: Timespec,
+    pub size: u64,
+    // its name in `.revisions`, see `file_name`
+    pub name: String,
+}
+
+impl Revision {
+    pub fn new(id: String, modified: Timespec, size: u64, earlier: &[Revision]) -> Revision {
+        let mut name = format!("{}", time::at_utc(modified).rfc3339());
+        // revisions saved within the same second are told apart by their IDs
+        if earlier.iter().any(|revision| revision.name == name) {
+            name = format!("{} {}", name, id);
+        }
+
+        Revision {
+            id: id,
+            modified: modified,
+            size: size,
+            name: name,
+        }
+    }
+
+    /// The revision's name in `.revisions`, the UTC time it was made, followed by its ID
+    /// if an earlier revision was made in the same second.
+    pub fn file_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+// a file in the trash, as files.list reports it
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct TrashedFileResponse {
+    pub id: String,
+    pub name: String,
+    pub mimeType: String,
+    pub size: Option<u64>,
+    pub modifiedTime: Option<String>,
+    // false for files that are only in the trash because a folder they're in is
+    pub explicitlyTrashed: Option<bool>,
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct TrashListResponse {
+    pub files: Vec<TrashedFileResponse>,
+    // set when there are more files than fit in one page
+    pub nextPageToken: Option<String>,
+}
+
+/// A file in Drive's trash, as it's listed in `.Trash`.
+#[derive (Debug, Clone)]
+pub struct TrashedFile {
+    pub id: String,
+    pub name: String,
+    pub kind: FileType,
+    pub size: u64,
+    pub modified: Option<Timespec>,
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct SearchFileResponse {
+    pub id: String,
+    pub name: String,
+    pub mimeType: String,
+    pub size: Option<u64>,
+    pub modifiedTime: Option<String>,
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct SearchListResponse {
+    pub files: Vec<SearchFileResponse>,
+}
+
+/// A file that matched a search, and the file in the mount it is, if it's in the mount.
+#[derive (Debug, Clone)]
+pub struct SearchResult {
+    pub id: String,
+    pub name: String,
+    pub uuid: Option<uuid::Uuid>,
+    pub mime_type: String,
+    // the name it's read as through a view, with the extension of its export for a Google
+    // file. None when it can't be read through one
+    pub local_name: Option<String>,
+    pub kind: FileType,
+    // 0 for Google files, which have no size until they're exported
+    pub size: u64,
+    pub modified: Option<Timespec>,
+}
+
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct AboutResponse {
+    pub storageQuota: StorageQuota,
+}
+
+// bytes of storage the signed in user has and uses, across all of Google's services
+#[derive (RustcDecodable, Debug, Clone)]
+pub struct StorageQuota {
+    // missing for accounts with unlimited storage
+    pub limit: Option<u64>,
+    pub usage: Option<u64>,
+    pub usageInDrive: Option<u64>,
+    pub usageInDriveTrash: Option<u64>,
+}
+
+/// Room left for files, on Drive and on the disk the local copies are kept on.
+#[derive (Debug, Clone)]
+pub struct StorageSpace {
+    // None when the Drive quota is unlimited
+    pub quota_limit: Option<u64>,
+    pub quota_usage: u64,
+    pub local_total: u64,
+    pub local_free: u64,
+}
+
 #[derive (RustcDecodable, RustcEncodable, Debug, Clone)]
 pub struct FileCheckResponse {
     pub md5Checksum: String,
     pub size: u64,
+    pub version: Option<String>,
 }
 
 #[derive (Clone)]
@@ -124,6 +430,28 @@ pub enum DriveErrorType {
     NoPathForParent,
     FileNotYetDownloaded,
     WrongSourceDataType,
+    InvalidMountOption(String),
+    UploadFailed,
+    UploadSessionExpired,
+    FailedDownload,
+    NotALink,
+    NoSuchFileHandle,
+    PermissionDenied,
+    // the write would take more than the room left on the local disk
+    NoSpace,
+    // the write, or an upload, would take Drive over the storage quota
+    QuotaExceeded,
+    NoSuchXattr,
+    ReadOnlyXattr,
+    InvalidXattrValue,
+    // the file has no ID to make changes on Drive with until its first upload finishes
+    NotYetUploaded,
+    // the change was asked of something that can only be looked at, like a revision
+    ReadOnly,
+    // there's already a file by that name in the folder
+    FileExists,
+    // Drive wouldn't run a search, most likely because the query isn't one it understands
+    InvalidQuery,
 }
 
 #[derive (Debug)]
@@ -185,3 +513,26 @@ impl From<rustc_serialize::json::EncoderError> for DriveError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use time::Timespec;
+    use super::*;
+
+    #[test]
+    fn revisions_are_named_by_time() {
+        let revision = Revision::new("a1".to_string(), Timespec::new(1463850361, 428000000), 10, &[]);
+        assert_eq!(revision.file_name(), "2016-05-21T17:06:01Z");
+    }
+
+    #[test]
+    fn revisions_in_the_same_second_get_their_id() {
+        let first = Revision::new("a1".to_string(), Timespec::new(1463850361, 0), 10, &[]);
+        let second = Revision::new("b2".to_string(), Timespec::new(1463850361, 500000000), 12, &[first.clone()]);
+        assert_eq!(first.file_name(), "2016-05-21T17:06:01Z");
+        assert_eq!(second.file_name(), "2016-05-21T17:06:01Z b2");
+
+        let third = Revision::new("c3".to_string(), Timespec::new(1463850362, 0), 14, &[first, second]);
+        assert_eq!(third.file_name(), "2016-05-21T17:06:02Z");
+    }
+}
@@ -0,0 +1,349 @@
+extern crate uuid;
+extern crate rusqlite;
+
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+use time;
+use uuid::Uuid;
+
+use types::*;
+use config::MountConfig;
+use drive::DriveFileDownloader;
+use filetree::FileDownloader;
+
+pub const CREATE_UPLOADS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS uploads (
+    uuid         BLOB PRIMARY KEY,
+    queued_at    INTEGER NOT NULL,
+    next_attempt INTEGER NOT NULL,
+    attempts     INTEGER NOT NULL DEFAULT 0,
+    last_error   TEXT,
+    session_uri  TEXT,
+    -- set when only the file's metadata has changed
+    metadata_only INTEGER NOT NULL DEFAULT 0,
+    -- what kind of error the last attempt ran into, for `driver status`
+    error_code   TEXT
+)";
+
+/// The `error_code` of an upload Drive turned down for going over the storage quota.
+pub const QUOTA_EXCEEDED: &'static str = "quota_exceeded";
+
+#[derive (Debug, Clone)]
+pub struct PendingUpload {
+    pub uuid: Uuid,
+    pub path: Option<String>,
+    pub queued_at: i64,
+    pub next_attempt: i64,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    // resumable upload session the file is being sent through, if one was started
+    pub session_uri: Option<String>,
+    pub metadata_only: bool,
+    pub error_code: Option<String>,
+}
+
+impl PendingUpload {
+    pub fn over_quota(&self) -> bool {
+        self.error_code.as_ref().map(|code| code == QUOTA_EXCEEDED).unwrap_or(false)
+    }
+}
+
+// the stable name of the errors `driver status` tells apart from the rest
+fn error_code(kind: &DriveErrorType) -> Option<&'static str> {
+    match *kind {
+        DriveErrorType::QuotaExceeded => Some(QUOTA_EXCEEDED),
+        _ => None,
+    }
+}
+
+// how long to wait before retrying an upload that failed `attempts` times: the quiet
+// period, doubled with every attempt, up to `max_backoff`
+fn backoff(delay: u64, max_backoff: u64, attempts: i64) -> u64 {
+    let doublings = cmp::max(cmp::min(attempts, 16), 0) as u32;
+    cmp::min(cmp::max(delay, 1).saturating_mul(1 << doublings), max_backoff)
+}
+
+fn now_nsec() -> i64 {
+    let ts = time::get_time();
+    ts.sec * 1000000000 + ts.nsec as i64
+}
+
+/// Records a file as dirty. A file stays dirty, and so won't be replaced by its copy on
+/// Drive, from its first unsynced write until its upload finishes. Queueing an already
+/// pending file restarts its quiet period, so a file that is being written to
+/// continuously won't be uploaded until it settles. Any upload session started for older
+/// contents of the file is dropped.
+pub fn queue_upload(conn: &rusqlite::Connection, uuid: &Uuid, delay: u64) -> Result<(), DriveError> {
+    try!(conn.execute("INSERT OR REPLACE INTO uploads (uuid, queued_at, next_attempt, attempts)
+                       VALUES ($1, $2, $3, 0)"
+        , &[ &uuid.clone().as_bytes().to_vec()
+           , &now_nsec()
+           , &(time::get_time().sec + delay as i64)
+           ]
+    ));
+
+    Ok(())
+}
+
+/// Records that a file's metadata changed. This is a no-op for a file whose contents are
+/// already waiting to be uploaded, as its metadata will go along with them.
+pub fn queue_metadata_update(conn: &rusqlite::Connection, uuid: &Uuid, delay: u64) -> Result<(), DriveError> {
+    try!(conn.execute("INSERT OR IGNORE INTO uploads (uuid, queued_at, next_attempt, attempts, metadata_only)
+                       VALUES ($1, $2, $3, 0, 1)"
+        , &[ &uuid.clone().as_bytes().to_vec()
+           , &now_nsec()
+           , &(time::get_time().sec + delay as i64)
+           ]
+    ));
+
+    Ok(())
+}
+
+/// Restarts the quiet period of a file, if it is waiting to be uploaded.
+pub fn delay_upload(conn: &rusqlite::Connection, uuid: &Uuid, delay: u64) -> Result<(), DriveError> {
+    try!(conn.execute("UPDATE uploads
+                       SET next_attempt=$1
+                       WHERE uuid=$2 AND attempts=0"
+        , &[ &(time::get_time().sec + delay as i64)
+           , &uuid.clone().as_bytes().to_vec()
+           ]
+    ));
+
+    Ok(())
+}
+
+pub fn dequeue_upload(conn: &rusqlite::Connection, uuid: &Uuid) -> Result<(), DriveError> {
+    try!(conn.execute("DELETE FROM uploads WHERE uuid=$1"
+        , &[ &uuid.clone().as_bytes().to_vec() ]
+    ));
+
+    Ok(())
+}
+
+/// Whether a file has contents waiting to be uploaded. Changes to its metadata alone
+/// don't count, as they leave the local copy as it was on Drive.
+pub fn is_dirty(conn: &rusqlite::Connection, uuid: &Uuid) -> Result<bool, DriveError> {
+    let count = try!(conn.query_row_named("SELECT COUNT(*) FROM uploads WHERE uuid=:uuid AND metadata_only=0"
+        , &[(":uuid", &uuid.clone().as_bytes().to_vec())]
+        , |row| -> i64 { row.get(0) }
+    ));
+
+    Ok(count > 0)
+}
+
+pub fn pending_uploads(conn: &rusqlite::Connection) -> Result<Vec<PendingUpload>, DriveError> {
+    query_uploads(conn, i64::max_value())
+}
+
+fn query_uploads(conn: &rusqlite::Connection, due_by: i64) -> Result<Vec<PendingUpload>, DriveError> {
+    let mut stmt = try!(conn.prepare("SELECT uploads.uuid, files.path, queued_at, next_attempt, attempts, last_error, session_uri, metadata_only, error_code
+                                      FROM uploads LEFT JOIN files ON files.uuid = uploads.uuid
+                                      WHERE next_attempt <= :due_by
+                                      ORDER BY next_attempt"));
+    let rows = try!(stmt.query_map_named(&[(":due_by", &due_by)]
+        , |row| -> PendingUpload {
+            PendingUpload {
+                uuid: Uuid::from_bytes(&row.get::<i32, Vec<u8>>(0)).expect("failed to parse Uuid from upload queue"),
+                path: row.get(1),
+                queued_at: row.get(2),
+                next_attempt: row.get(3),
+                attempts: row.get(4),
+                last_error: row.get(5),
+                session_uri: row.get(6),
+                metadata_only: row.get::<i32, i64>(7) != 0,
+                error_code: row.get(8),
+            }
+        }
+    ));
+
+    let mut uploads = Vec::new();
+    for upload in rows {
+        uploads.push(try!(upload));
+    }
+
+    Ok(uploads)
+}
+
+/// Sends queued files to Drive once their quiet period has passed. Meant to be run on
+/// its own thread, with its own connections to Drive and the drive db.
+pub struct Uploader {
+    downloader: DriveFileDownloader,
+    conn: rusqlite::Connection,
+    config: MountConfig,
+}
+
+impl Uploader {
+    pub fn new(downloader: DriveFileDownloader, conn: rusqlite::Connection, config: MountConfig) -> Uploader {
+        Uploader {
+            downloader: downloader,
+            conn: conn,
+            config: config,
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            self.upload_due_files().unwrap_or_else(|err| {
+                println!("couldn't read upload queue, err: {:?}", err);
+            });
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    fn upload_due_files(&mut self) -> Result<(), DriveError> {
+        for upload in try!(query_uploads(&self.conn, time::get_time().sec)) {
+            println!("uploading {:?}, attempt {}", upload.path, upload.attempts + 1);
+
+            match self.downloader.upload_file(&upload) {
+                Ok(_) => {
+                    // only forget the entry if the file wasn't written to again while it was
+                    // being uploaded
+                    try!(self.conn.execute("DELETE FROM uploads WHERE uuid=$1 AND queued_at=$2"
+                        , &[ &upload.uuid.clone().as_bytes().to_vec()
+                           , &upload.queued_at
+                           ]
+                    ));
+                },
+                Err(err) => {
+                    println!("upload of {:?} failed, err: {:?}", upload.path, err);
+                    if let Some(ref resp) = err.response {
+                        // refresh our credentials, if that was the problem, before the next try
+                        self.downloader.resolve_error(resp).unwrap_or_else(|err| {
+                            println!("couldn't resolve upload error, err: {:?}", err);
+                        });
+                    }
+
+                    let backoff = backoff(self.config.upload_delay, self.config.upload_max_backoff, upload.attempts);
+                    try!(self.conn.execute("UPDATE uploads
+                                            SET attempts=attempts+1, next_attempt=$1, last_error=$2, error_code=$3
+                                            WHERE uuid=$4 AND queued_at=$5"
+                        , &[ &(time::get_time().sec + backoff as i64)
+                           , &format!("{:?}", err.kind)
+                           , &error_code(&err.kind)
+                           , &upload.uuid.clone().as_bytes().to_vec()
+                           , &upload.queued_at
+                           ]
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use uuid::Uuid;
+    use types::*;
+    use super::*;
+
+    fn failed_upload(error_code: Option<&str>) -> PendingUpload {
+        PendingUpload {
+            uuid: Uuid::new_v4(),
+            path: None,
+            queued_at: 0,
+            next_attempt: 0,
+            attempts: 1,
+            last_error: None,
+            session_uri: None,
+            metadata_only: false,
+            error_code: error_code.map(|code| code.to_string()),
+        }
+    }
+
+    fn queue_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE files (uuid BLOB, path TEXT)", &[]).unwrap();
+        conn.execute(CREATE_UPLOADS_TABLE, &[]).unwrap();
+        conn
+    }
+
+    fn queued(conn: &Connection, uuid: &Uuid) -> Option<PendingUpload> {
+        pending_uploads(conn).unwrap().into_iter().find(|upload| upload.uuid == *uuid)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        assert_eq!(backoff(5, 600, 0), 5);
+        assert_eq!(backoff(5, 600, 1), 10);
+        assert_eq!(backoff(5, 600, 3), 40);
+        assert_eq!(backoff(5, 600, 7), 600);
+        // a zero delay still backs off
+        assert_eq!(backoff(0, 600, 0), 1);
+        assert_eq!(backoff(0, 600, 2), 4);
+        // the shift is capped, so many attempts don't overflow
+        assert_eq!(backoff(5, u64::max_value(), 1000), 5 << 16);
+        assert_eq!(backoff(u64::max_value(), u64::max_value(), 16), u64::max_value());
+    }
+
+    #[test]
+    fn queueing_again_restarts_the_upload() {
+        let conn = queue_db();
+        let uuid = Uuid::new_v4();
+        queue_upload(&conn, &uuid, 5).unwrap();
+        conn.execute("UPDATE uploads SET attempts=3, session_uri='https://example.com/session'", &[]).unwrap();
+
+        queue_upload(&conn, &uuid, 5).unwrap();
+        let upload = queued(&conn, &uuid).unwrap();
+        assert_eq!(upload.attempts, 0);
+        assert_eq!(upload.session_uri, None);
+        assert_eq!(pending_uploads(&conn).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn metadata_updates_dont_replace_uploads() {
+        let conn = queue_db();
+        let uuid = Uuid::new_v4();
+        queue_upload(&conn, &uuid, 5).unwrap();
+        queue_metadata_update(&conn, &uuid, 5).unwrap();
+        assert!(!queued(&conn, &uuid).unwrap().metadata_only);
+
+        // but new contents take over a metadata update
+        let other = Uuid::new_v4();
+        queue_metadata_update(&conn, &other, 5).unwrap();
+        assert!(queued(&conn, &other).unwrap().metadata_only);
+        queue_upload(&conn, &other, 5).unwrap();
+        assert!(!queued(&conn, &other).unwrap().metadata_only);
+    }
+
+    #[test]
+    fn delaying_only_moves_untried_uploads() {
+        let conn = queue_db();
+        let uuid = Uuid::new_v4();
+        queue_upload(&conn, &uuid, 0).unwrap();
+        let next_attempt = queued(&conn, &uuid).unwrap().next_attempt;
+        delay_upload(&conn, &uuid, 100).unwrap();
+        assert!(queued(&conn, &uuid).unwrap().next_attempt >= next_attempt + 100);
+
+        // a failed upload keeps its backoff
+        conn.execute("UPDATE uploads SET attempts=1, next_attempt=0", &[]).unwrap();
+        delay_upload(&conn, &uuid, 100).unwrap();
+        assert_eq!(queued(&conn, &uuid).unwrap().next_attempt, 0);
+    }
+
+    #[test]
+    fn metadata_only_files_arent_dirty() {
+        let conn = queue_db();
+        let uuid = Uuid::new_v4();
+        assert!(!is_dirty(&conn, &uuid).unwrap());
+        queue_metadata_update(&conn, &uuid, 5).unwrap();
+        assert!(!is_dirty(&conn, &uuid).unwrap());
+        queue_upload(&conn, &uuid, 5).unwrap();
+        assert!(is_dirty(&conn, &uuid).unwrap());
+        dequeue_upload(&conn, &uuid).unwrap();
+        assert!(!is_dirty(&conn, &uuid).unwrap());
+    }
+
+    #[test]
+    fn over_quota() {
+        assert_eq!(error_code(&DriveErrorType::QuotaExceeded), Some(QUOTA_EXCEEDED));
+        assert_eq!(error_code(&DriveErrorType::UploadFailed), None);
+
+        assert!(failed_upload(Some(QUOTA_EXCEEDED)).over_quota());
+        assert!(!failed_upload(Some("something_else")).over_quota());
+        assert!(!failed_upload(None).over_quota());
+    }
+}
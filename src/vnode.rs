@@ -0,0 +1,582 @@
+use std::collections::hash_map::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use libc;
+use fuse::FileAttr;
+use uuid::Uuid;
+
+use types::*;
+use filetree::*;
+use config::View;
+
+/// Inodes of virtual nodes start here, well clear of the ones `FileTree.current_inode`
+/// hands out to files in the tree.
+pub const VIRTUAL_INODE_BASE: u64 = 1 << 48;
+
+/// Looking this name up in any folder gives the revisions of the files in it. It isn't
+/// listed, so that tools walking the tree don't download every revision of everything.
+pub const REVISIONS_DIR: &'static str = ".revisions";
+
+/// Drive's trash, listed at the root of the mount.
+pub const TRASH_DIR: &'static str = ".Trash";
+
+/// Looking a Drive query up in this folder at the root, e.g.
+/// `.search/fullText contains 'invoice'`, gives a folder of the files that match.
+pub const SEARCH_DIR: &'static str = ".search";
+
+const ROOT_INODE: u64 = 1;
+
+/// Something in the mount that isn't a file on Drive, but a view of one.
+#[derive (Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VirtualNode {
+    // the `.revisions` folder in the folder with this inode
+    RevisionsDir(u64),
+    // the folder with the revisions of the file with this inode
+    FileRevisions(u64),
+    // a revision, by its ID, of the file with this inode
+    Revision(u64, String),
+    // `.Trash`
+    Trash,
+    // a file in the trash, by its Drive ID
+    TrashedFile(String),
+    // `.search`
+    Search,
+    // the files matching a Drive query
+    SearchResults(String),
+    // one of the views turned on in the config, at the root
+    View(View),
+    // a file or folder in a view that isn't in the mount, by its Drive ID and MIME type
+    RemoteFile(String, String),
+}
+
+pub struct VirtualEntry {
+    pub node: VirtualNode,
+    // the folder it was found in, which may be virtual too, and its name there
+    pub parent: u64,
+    pub name: PathBuf,
+    pub attr: FileAttr,
+}
+
+/// The virtual nodes that have been looked up so far. A node keeps its inode for as long
+/// as the mount runs, so the kernel sees the same file each time it's looked up.
+pub struct VirtualNodes {
+    entries: HashMap<u64, VirtualEntry>,
+    inodes: HashMap<VirtualNode, u64>,
+    // inodes by the folder they were last found in, and their name there
+    names: HashMap<(u64, PathBuf), u64>,
+    next_ino: u64,
+    views: Vec<View>,
+}
+
+impl VirtualNodes {
+    pub fn new(views: Vec<View>) -> VirtualNodes {
+        VirtualNodes {
+            entries: HashMap::new(),
+            inodes: HashMap::new(),
+            names: HashMap::new(),
+            next_ino: VIRTUAL_INODE_BASE,
+            views: views,
+        }
+    }
+
+    /// The view whose folder at the root is called `name`, if it's turned on.
+    pub fn view_named(&self, name: &Path) -> Option<View> {
+        self.views.iter().find(|view| name == Path::new(view.dir_name())).cloned()
+    }
+
+    pub fn is_virtual(ino: u64) -> bool {
+        ino >= VIRTUAL_INODE_BASE
+    }
+
+    pub fn get(&self, ino: u64) -> Option<&VirtualEntry> {
+        self.entries.get(&ino)
+    }
+
+    /// The entry of `node`, if it's been looked up before.
+    pub fn find(&self, node: &VirtualNode) -> Option<&VirtualEntry> {
+        self.inodes.get(node).and_then(|ino| self.entries.get(ino))
+    }
+
+    /// The entry called `name` in the virtual folder `parent`, if it's been listed or
+    /// looked up before.
+    pub fn child(&self, parent: u64, name: &Path) -> Option<&VirtualEntry> {
+        self.names.get(&(parent, name.to_owned()))
+            .and_then(|ino| self.entries.get(ino))
+            // a node found again somewhere else is only there now
+            .and_then(|entry| if entry.parent == parent && entry.name == name { Some(entry) } else { None })
+    }
+
+    /// Gives `node` its inode, and sets its attributes to `attr` with the inode filled in.
+    pub fn insert(&mut self, node: VirtualNode, parent: u64, name: &Path, mut attr: FileAttr) -> FileAttr {
+        let ino = match self.inodes.get(&node) {
+            Some(ino) => *ino,
+            None => {
+                let ino = self.next_ino;
+                self.next_ino += 1;
+                self.inodes.insert(node.clone(), ino);
+                ino
+            }
+        };
+
+        attr.ino = ino;
+        self.names.insert((parent, name.to_owned()), ino);
+        self.entries.insert(ino, VirtualEntry {
+            node: node,
+            parent: parent,
+            name: name.to_owned(),
+            attr: attr,
+        });
+        attr
+    }
+
+    /// Updates the size of a node once it's known, like that of an exported Google file.
+    pub fn set_size(&mut self, ino: u64, size: u64) {
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            entry.attr.size = size;
+            entry.attr.blocks = size/512;
+        }
+    }
+
+    /// Forgets a node that's gone, like a file that's been taken out of the trash.
+    pub fn remove(&mut self, node: &VirtualNode) {
+        if let Some(ino) = self.inodes.remove(node) {
+            if let Some(entry) = self.entries.remove(&ino) {
+                self.names.remove(&(entry.parent, entry.name));
+            }
+        }
+    }
+}
+
+// a read-only folder, owned by whoever owns the file it's a view of
+fn dir_attr(of: &FileAttr) -> FileAttr {
+    FileAttr {
+        size: 0,
+        blocks: 0,
+        kind: FileType::Directory,
+        perm: 0o555,
+        nlink: 2,
+        .. *of
+    }
+}
+
+// things can be moved out of the trash, or deleted from it
+fn trash_attr(root: &FileAttr) -> FileAttr {
+    FileAttr { perm: 0o755, .. dir_attr(root) }
+}
+
+fn revision_attr(of: &FileAttr, revision: &Revision) -> FileAttr {
+    FileAttr {
+        size: revision.size,
+        blocks: revision.size/512,
+        atime: revision.modified,
+        mtime: revision.modified,
+        ctime: revision.modified,
+        crtime: revision.modified,
+        kind: FileType::RegularFile,
+        perm: 0o444,
+        nlink: 1,
+        .. *of
+    }
+}
+
+// trashed files keep the owner of the root, as they're no longer anywhere in the tree
+fn trashed_attr(root: &FileAttr, file: &TrashedFile) -> FileAttr {
+    let modified = file.modified.unwrap_or(root.mtime);
+    FileAttr {
+        size: file.size,
+        blocks: file.size/512,
+        atime: modified,
+        mtime: modified,
+        ctime: modified,
+        crtime: modified,
+        kind: file.kind,
+        perm: if file.kind == FileType::Directory { 0o755 } else { 0o644 },
+        nlink: 1,
+        .. *root
+    }
+}
+
+// files in a view that aren't in the mount can only be read, and have the owner of the root
+fn remote_attr(root: &FileAttr, result: &SearchResult) -> FileAttr {
+    let modified = result.modified.unwrap_or(root.mtime);
+    FileAttr {
+        size: result.size,
+        blocks: result.size/512,
+        atime: modified,
+        mtime: modified,
+        ctime: modified,
+        crtime: modified,
+        kind: result.kind,
+        perm: if result.kind == FileType::Directory { 0o555 } else { 0o444 },
+        nlink: 1,
+        .. *root
+    }
+}
+
+// the query for what's in a folder on Drive
+fn children_query(id: &str) -> String {
+    format!("'{}' in parents", id)
+}
+
+// the folder in the trash whose contents a virtual folder lists, None for the trash
+// itself, or nothing when the node isn't part of the trash
+fn trash_folder(node: &VirtualNode) -> Option<Option<String>> {
+    match *node {
+        VirtualNode::Trash => Some(None),
+        VirtualNode::TrashedFile(ref id) => Some(Some(id.clone())),
+        _ => None,
+    }
+}
+
+fn read_only() -> DriveError {
+    DriveError {
+        kind: DriveErrorType::ReadOnly,
+        response: None,
+    }
+}
+
+fn no_such_inode() -> DriveError {
+    DriveError {
+        kind: DriveErrorType::NoSuchInode,
+        response: None,
+    }
+}
+
+impl<'b> FileTree<'b> {
+    /// Whether looking `name` up in `parent` leads into the virtual nodes, rather than the
+    /// tree. Real files called `.revisions`, or `.Trash`, `.search` and the names of the
+    /// views at the root, are hidden by the virtual folders.
+    pub fn is_virtual_lookup(&self, parent: u64, name: &Path) -> bool {
+        VirtualNodes::is_virtual(parent)
+        || name == Path::new(REVISIONS_DIR)
+        || (parent == ROOT_INODE && (name == Path::new(TRASH_DIR)
+                                     || name == Path::new(SEARCH_DIR)
+                                     || self.virtual_nodes.view_named(name).is_some()))
+    }
+
+    pub fn lookup_virtual(&mut self, parent: u64, name: &Path) -> Result<FileAttr, DriveError> {
+        let parent_node = self.virtual_nodes.get(parent).map(|entry| entry.node.clone());
+        match parent_node {
+            None => {
+                let dir = try!(self.inode_map.get(&parent).ok_or(no_such_inode())).attr;
+                if dir.kind != FileType::Directory {
+                    return Err(no_such_inode())
+                }
+                if let Some(view) = self.virtual_nodes.view_named(name) {
+                    return Ok(self.virtual_nodes.insert(VirtualNode::View(view), parent, name, dir_attr(&dir)))
+                }
+                if name == Path::new(TRASH_DIR) {
+                    return Ok(self.virtual_nodes.insert(VirtualNode::Trash, parent, name, trash_attr(&dir)))
+                }
+                if name == Path::new(SEARCH_DIR) {
+                    return Ok(self.virtual_nodes.insert(VirtualNode::Search, parent, name, dir_attr(&dir)))
+                }
+                Ok(self.virtual_nodes.insert(VirtualNode::RevisionsDir(parent), parent, name, dir_attr(&dir)))
+            },
+            Some(VirtualNode::RevisionsDir(dir)) => {
+                let file_ino = try!(self.child_by_name(dir, name).ok_or(no_such_inode()));
+                let file = try!(self.inode_map.get(&file_ino).ok_or(no_such_inode())).attr;
+                if file.kind != FileType::RegularFile {
+                    return Err(no_such_inode())
+                }
+                Ok(self.virtual_nodes.insert(VirtualNode::FileRevisions(file_ino), parent, name, dir_attr(&file)))
+            },
+            Some(VirtualNode::FileRevisions(file_ino)) => {
+                let (uuid, file) = {
+                    let fd = try!(self.inode_map.get(&file_ino).ok_or(no_such_inode()));
+                    (fd.id.clone(), fd.attr)
+                };
+                let revisions = try!(self.file_downloader.list_revisions(&uuid));
+                let revision = try!(revisions.iter()
+                    .find(|revision| Path::new(&revision.file_name()) == name)
+                    .ok_or(no_such_inode()));
+                Ok(self.virtual_nodes.insert(VirtualNode::Revision(file_ino, revision.id.clone())
+                                            , parent
+                                            , name
+                                            , revision_attr(&file, revision)))
+            },
+            Some(VirtualNode::Revision(..)) => Err(no_such_inode()),
+            Some(VirtualNode::Search) => {
+                let query = try!(name.to_str().ok_or(no_such_inode())).to_string();
+                // a query Drive won't run fails here, rather than as an empty folder
+                try!(self.file_downloader.search(&query, None));
+                let root = try!(self.inode_map.get(&ROOT_INODE).ok_or(no_such_inode())).attr;
+                Ok(self.virtual_nodes.insert(VirtualNode::SearchResults(query), parent, name, dir_attr(&root)))
+            },
+            // the results are the files themselves, so they can be used like anywhere else
+            Some(VirtualNode::SearchResults(query)) => self.lookup_result(parent, &query, None, false, name),
+            Some(VirtualNode::View(view)) => {
+                let (query, order_by) = view.query();
+                self.lookup_result(parent, query, order_by, true, name)
+            },
+            Some(VirtualNode::RemoteFile(id, _)) => self.lookup_result(parent, &children_query(&id), None, true, name),
+            Some(node) => {
+                let folder = try!(trash_folder(&node).ok_or(no_such_inode()));
+                // `ls -l` looks up everything it's just listed, which needn't list it again
+                if let Some(entry) = self.virtual_nodes.child(parent, name) {
+                    return Ok(entry.attr)
+                }
+                let root = try!(self.inode_map.get(&ROOT_INODE).ok_or(no_such_inode())).attr;
+                let trashed = try!(self.file_downloader.list_trash(folder.as_ref().map(|id| id.as_str())));
+                let file = try!(trashed.iter()
+                    .find(|file| Path::new(&file.name) == name)
+                    .ok_or(no_such_inode()));
+                Ok(self.virtual_nodes.insert(VirtualNode::TrashedFile(file.id.clone())
+                                            , parent
+                                            , name
+                                            , trashed_attr(&root, file)))
+            },
+        }
+    }
+
+    /// The entries of a virtual folder, along with the inode of the folder it's in.
+    pub fn read_virtual_dir(&mut self, ino: u64) -> Result<(u64, Vec<(u64, FileType, PathBuf)>), DriveError> {
+        let (node, parent) = {
+            let entry = try!(self.virtual_nodes.get(ino).ok_or(no_such_inode()));
+            (entry.node.clone(), entry.parent)
+        };
+
+        let mut entries = Vec::new();
+        match node {
+            VirtualNode::RevisionsDir(dir) => {
+                let children = self.child_map.get(&dir).cloned().unwrap_or(Vec::new());
+                for child in children {
+                    let (name, attr) = match self.inode_map.get(&child) {
+                        Some(fd) if fd.attr.kind == FileType::RegularFile => {
+                            (fd.path.file_name().map(|name| PathBuf::from(name)), fd.attr)
+                        },
+                        _ => continue,
+                    };
+                    if let Some(name) = name {
+                        let attr = self.virtual_nodes.insert(VirtualNode::FileRevisions(child), ino, &name, dir_attr(&attr));
+                        entries.push((attr.ino, FileType::Directory, name));
+                    }
+                }
+            },
+            VirtualNode::FileRevisions(file_ino) => {
+                let (uuid, file) = {
+                    let fd = try!(self.inode_map.get(&file_ino).ok_or(no_such_inode()));
+                    (fd.id.clone(), fd.attr)
+                };
+                for revision in try!(self.file_downloader.list_revisions(&uuid)) {
+                    let name = PathBuf::from(revision.file_name());
+                    let attr = self.virtual_nodes.insert(VirtualNode::Revision(file_ino, revision.id.clone())
+                                                        , ino
+                                                        , &name
+                                                        , revision_attr(&file, &revision));
+                    entries.push((attr.ino, FileType::RegularFile, name));
+                }
+            },
+            VirtualNode::Revision(..) => return Err(no_such_inode()),
+            // searches are only run by looking them up
+            VirtualNode::Search => { },
+            VirtualNode::SearchResults(query) => entries = try!(self.query_results(ino, &query, None, false)),
+            VirtualNode::View(view) => {
+                let (query, order_by) = view.query();
+                entries = try!(self.query_results(ino, query, order_by, true));
+            },
+            VirtualNode::RemoteFile(id, _) => entries = try!(self.query_results(ino, &children_query(&id), None, true)),
+            node => {
+                let folder = try!(trash_folder(&node).ok_or(no_such_inode()));
+                let root = try!(self.inode_map.get(&ROOT_INODE).ok_or(no_such_inode())).attr;
+                for file in try!(self.file_downloader.list_trash(folder.as_ref().map(|id| id.as_str()))) {
+                    let name = PathBuf::from(&file.name);
+                    let attr = self.virtual_nodes.insert(VirtualNode::TrashedFile(file.id.clone())
+                                                        , ino
+                                                        , &name
+                                                        , trashed_attr(&root, &file));
+                    entries.push((attr.ino, file.kind, name));
+                }
+            },
+        }
+
+        Ok((parent, entries))
+    }
+
+    /// Opens a virtual file for reading, fetching its contents from Drive first. Returns
+    /// an fh that `read_handle` and `release_handle` take like any other, and whether
+    /// it's to be read without the page cache, as the size the kernel was given for it
+    /// may be wrong.
+    pub fn open_virtual(&mut self, ino: u64, flags: u32) -> Result<(u64, bool), DriveError> {
+        if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(read_only())
+        }
+
+        let node = try!(self.virtual_nodes.get(ino).ok_or(no_such_inode())).node.clone();
+        let (uuid, path, direct_io) = match node {
+            VirtualNode::Revision(file_ino, revision_id) => {
+                let uuid = try!(self.file_uuid(file_ino));
+                let path = try!(self.file_downloader.retreive_revision(&uuid, &revision_id));
+                (uuid, path, false)
+            },
+            // not in the mount, so there's no uuid for it. Google files are listed with no
+            // size, which is only known once they've been exported
+            VirtualNode::RemoteFile(id, mime_type) => {
+                let path = try!(self.file_downloader.retreive_remote(&id, &mime_type));
+                (Uuid::nil(), path, true)
+            },
+            // trashed files are only there to be taken out of the trash, or deleted
+            VirtualNode::TrashedFile(_) => return Err(DriveError {
+                kind: DriveErrorType::PermissionDenied,
+                response: None,
+            }),
+            _ => return Err(no_such_inode()),
+        };
+        let file = try!(File::open(&path));
+        if direct_io {
+            let size = try!(file.metadata()).len();
+            self.virtual_nodes.set_size(ino, size);
+        }
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, FileHandle {
+            ino: ino,
+            uuid: uuid,
+            file: file,
+            flags: flags,
+            dirty: false,
+            read_ahead: 0,
+        });
+
+        Ok((fh, direct_io))
+    }
+
+    /// The virtual folders listed at the root: `.Trash`, and the folders of the views
+    /// that are turned on.
+    pub fn root_virtual_dirs(&mut self) -> Vec<(u64, FileType, PathBuf)> {
+        let root = match self.inode_map.get(&ROOT_INODE) {
+            Some(fd) => fd.attr,
+            None => return Vec::new(),
+        };
+        let trash = self.virtual_nodes.insert(VirtualNode::Trash, ROOT_INODE, Path::new(TRASH_DIR), trash_attr(&root));
+        let mut dirs = vec![(trash.ino, FileType::Directory, PathBuf::from(TRASH_DIR))];
+
+        let views = self.virtual_nodes.views.clone();
+        dirs.extend(views.into_iter().map(|view| {
+            let attr = self.virtual_nodes.insert(VirtualNode::View(view), ROOT_INODE, Path::new(view.dir_name()), dir_attr(&root));
+            (attr.ino, FileType::Directory, PathBuf::from(view.dir_name()))
+        }));
+        dirs
+    }
+
+    // the files that match a query, listed in the virtual folder `dir`. Files in the tree
+    // keep their inode and their name in the tree; the others are left out, unless
+    // `remote` is set, when they're given virtual nodes. Only the first of several files
+    // with the same name is kept
+    fn query_results(&mut self, dir: u64, query: &str, order_by: Option<&str>, remote: bool) -> Result<Vec<(u64, FileType, PathBuf)>, DriveError> {
+        let root = try!(self.inode_map.get(&ROOT_INODE).ok_or(no_such_inode())).attr;
+        let mut results: Vec<(u64, FileType, PathBuf)> = Vec::new();
+        for result in try!(self.file_downloader.search(query, order_by)) {
+            let in_tree = result.uuid.and_then(|uuid| {
+                self.conn.query_row_named("SELECT ino FROM files WHERE uuid=:uuid"
+                    , &[( ":uuid", &uuid.as_bytes().to_vec() )]
+                    , |row| -> u64 { row.get::<i32, i64>(0) as u64 }
+                ).ok()
+            }).and_then(|ino| {
+                self.inode_map.get(&ino).and_then(|fd| {
+                    fd.path.file_name().map(|name| (ino, fd.attr.kind, PathBuf::from(name)))
+                })
+            });
+            let entry = match in_tree {
+                Some(entry) => entry,
+                None if remote => {
+                    let name = match result.local_name.clone() {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    let node = VirtualNode::RemoteFile(result.id.clone(), result.mime_type.clone());
+                    let mut attr = remote_attr(&root, &result);
+                    // an export keeps the size it had when it was last read
+                    if let Some(known) = self.virtual_nodes.find(&node) {
+                        if result.size == 0 {
+                            attr.size = known.attr.size;
+                            attr.blocks = known.attr.blocks;
+                        }
+                    }
+                    let name = PathBuf::from(name);
+                    let attr = self.virtual_nodes.insert(node, dir, &name, attr);
+                    (attr.ino, result.kind, name)
+                },
+                // found on Drive, but not in the mount
+                None => continue,
+            };
+            if !results.iter().any(|&(_, _, ref seen)| seen == &entry.2) {
+                results.push(entry);
+            }
+        }
+
+        Ok(results)
+    }
+
+    // the attributes of the file called `name` in the results of a query
+    fn lookup_result(&mut self, dir: u64, query: &str, order_by: Option<&str>, remote: bool, name: &Path) -> Result<FileAttr, DriveError> {
+        let ino = try!(try!(self.query_results(dir, query, order_by, remote)).into_iter()
+            .find(|&(_, _, ref result_name)| result_name == name)
+            .map(|(ino, _, _)| ino)
+            .ok_or(no_such_inode()));
+        if VirtualNodes::is_virtual(ino) {
+            Ok(try!(self.virtual_nodes.get(ino).ok_or(no_such_inode())).attr)
+        } else {
+            Ok(try!(self.inode_map.get(&ino).ok_or(no_such_inode())).attr)
+        }
+    }
+
+    // the Drive ID of the file called `name` in a folder of the trash
+    fn trashed_child(&mut self, parent: u64, name: &Path) -> Result<String, DriveError> {
+        let node = try!(self.virtual_nodes.get(parent).ok_or(no_such_inode())).node.clone();
+        let folder = try!(trash_folder(&node).ok_or(read_only()));
+        if let Some(&VirtualEntry { node: VirtualNode::TrashedFile(ref id), .. }) = self.virtual_nodes.child(parent, name) {
+            return Ok(id.clone())
+        }
+        let trashed = try!(self.file_downloader.list_trash(folder.as_ref().map(|id| id.as_str())));
+        trashed.into_iter()
+            .find(|file| Path::new(&file.name) == name)
+            .map(|file| file.id)
+            .ok_or(no_such_inode())
+    }
+
+    /// Deletes a file from the trash for good. Nothing else that's virtual can be removed.
+    pub fn remove_virtual(&mut self, parent: u64, name: &Path) -> Result<(), DriveError> {
+        let id = try!(self.trashed_child(parent, name));
+        try!(self.file_downloader.delete_forever(&id));
+        self.virtual_nodes.remove(&VirtualNode::TrashedFile(id));
+        Ok(())
+    }
+
+    /// Moves a file out of the trash, into the real folder `new_parent`. The file keeps
+    /// its Drive ID, so its sharing and revisions come back with it.
+    pub fn rename_virtual(&mut self, parent: u64, name: &Path, new_parent: u64, new_name: &Path) -> Result<(), DriveError> {
+        if VirtualNodes::is_virtual(new_parent) {
+            return Err(read_only())
+        }
+        if self.child_by_name(new_parent, new_name).is_some() {
+            return Err(DriveError {
+                kind: DriveErrorType::FileExists,
+                response: None,
+            })
+        }
+        let id = try!(self.trashed_child(parent, name));
+        let parent_uuid = try!(self.file_uuid(new_parent));
+        let new_name_str = try!(new_name.to_str().ok_or(DriveError {
+            kind: DriveErrorType::NoFileName,
+            response: None,
+        }));
+
+        if let Some(fr) = try!(self.file_downloader.untrash(&id, &parent_uuid, new_name_str)) {
+            let uuid = fr.uuid.clone();
+            let kind = fr.kind;
+            // a file trashed on Drive since the mount started is still where it was
+            try!(self.reparent(&uuid, new_parent));
+            try!(self.add_file(fr, new_parent));
+            let ino = try!(self.load_file(&uuid, new_parent));
+            if kind == FileType::Directory {
+                let path = try!(self.inode_map.get(&ino).ok_or(no_such_inode())).path.clone();
+                try!(self.get_files(&path, &uuid, ino));
+            }
+        }
+        self.virtual_nodes.remove(&VirtualNode::TrashedFile(id));
+        Ok(())
+    }
+}
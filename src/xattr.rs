@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use rustc_serialize::json::{Json, ToJson};
+
+use types::*;
+
+const USER_PREFIX: &'static str = "user.";
+const DRIVE_PREFIX: &'static str = "user.drive.";
+
+/// The extended attributes of a file in the mount. Those under `user.drive.` are fields of
+/// the file on Drive, and any other `user.` attribute is kept in the file's properties.
+#[derive (Debug, Clone, PartialEq)]
+pub enum Xattr {
+    Id,
+    MimeType,
+    Md5,
+    WebViewLink,
+    Owners,
+    Version,
+    Description,
+    Starred,
+    // who the file is shared with, as a JSON list of permissions
+    Permissions,
+    Property(String),
+}
+
+impl Xattr {
+    /// None for attributes outside the `user.` namespace, and for unknown `user.drive.`
+    /// attributes.
+    pub fn from_name(name: &str) -> Option<Xattr> {
+        if name.starts_with(DRIVE_PREFIX) {
+            match &name[DRIVE_PREFIX.len()..] {
+                "id"          => Some(Xattr::Id),
+                "mimetype"    => Some(Xattr::MimeType),
+                "md5"         => Some(Xattr::Md5),
+                "webViewLink" => Some(Xattr::WebViewLink),
+                "owners"      => Some(Xattr::Owners),
+                "version"     => Some(Xattr::Version),
+                "description" => Some(Xattr::Description),
+                "starred"     => Some(Xattr::Starred),
+                "permissions" => Some(Xattr::Permissions),
+                _ => None,
+            }
+        } else if name.starts_with(USER_PREFIX) && name.len() > USER_PREFIX.len() {
+            Some(Xattr::Property(name[USER_PREFIX.len()..].to_string()))
+        } else {
+            None
+        }
+    }
+
+    /// The name the attribute is read and written under.
+    pub fn name(&self) -> String {
+        let field = match *self {
+            Xattr::Id          => "id",
+            Xattr::MimeType    => "mimetype",
+            Xattr::Md5         => "md5",
+            Xattr::WebViewLink => "webViewLink",
+            Xattr::Owners      => "owners",
+            Xattr::Version     => "version",
+            Xattr::Description => "description",
+            Xattr::Starred     => "starred",
+            Xattr::Permissions => "permissions",
+            Xattr::Property(ref key) => return format!("{}{}", USER_PREFIX, key),
+        };
+        format!("{}{}", DRIVE_PREFIX, field)
+    }
+
+    /// The attributes a file has, as listxattr shows them: the fields it has a value for,
+    /// its permissions, and its properties. Properties named like a field are left out,
+    /// as the field would hide them.
+    pub fn all(fr: &DriveFileResponse) -> Vec<Xattr> {
+        let mut xattrs: Vec<Xattr> = vec![Xattr::Id, Xattr::MimeType, Xattr::Md5, Xattr::WebViewLink
+                                          , Xattr::Owners, Xattr::Version, Xattr::Description, Xattr::Starred]
+            .into_iter()
+            .filter(|xattr| xattr.value(fr).is_some())
+            .collect();
+        xattrs.push(Xattr::Permissions);
+        if let Some(ref properties) = fr.properties {
+            let mut keys: Vec<&String> = properties.keys()
+                .filter(|key| Xattr::from_name(&format!("{}{}", USER_PREFIX, key)) == Some(Xattr::Property((*key).clone())))
+                .collect();
+            keys.sort();
+            xattrs.extend(keys.into_iter().map(|key| Xattr::Property(key.clone())));
+        }
+        xattrs
+    }
+
+    pub fn is_writable(&self) -> bool {
+        match *self {
+            Xattr::Description | Xattr::Starred | Xattr::Property(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The attribute's value for a file, if it has one. Permissions aren't part of the
+    /// file's metadata, and have to be asked for separately.
+    pub fn value(&self, fr: &DriveFileResponse) -> Option<String> {
+        match *self {
+            Xattr::Id          => Some(fr.id.clone()),
+            Xattr::MimeType    => Some(fr.mimeType.clone()),
+            Xattr::Md5         => fr.md5Checksum.clone(),
+            Xattr::WebViewLink => fr.webViewLink.clone(),
+            Xattr::Owners      => fr.owners.as_ref().map(|owners| {
+                owners.iter()
+                      .filter_map(|owner| owner.emailAddress.clone().or(owner.displayName.clone()))
+                      .collect::<Vec<String>>()
+                      .join(", ")
+            }),
+            Xattr::Version     => fr.version.clone(),
+            Xattr::Description => fr.description.clone(),
+            Xattr::Starred     => fr.starred.map(|starred| starred.to_string()),
+            Xattr::Permissions => None,
+            Xattr::Property(ref key) => fr.properties.as_ref().and_then(|properties| properties.get(key).cloned()),
+        }
+    }
+
+    /// The metadata patch that sets the attribute to `value`, or clears it when `value` is
+    /// None.
+    pub fn patch(&self, value: Option<&str>) -> Result<BTreeMap<String, Json>, DriveError> {
+        let mut metadata = BTreeMap::new();
+        match *self {
+            Xattr::Description => {
+                metadata.insert("description".to_string(), value.map(|v| v.to_json()).unwrap_or(Json::Null));
+            },
+            Xattr::Starred => {
+                let starred = match value {
+                    Some("true") | Some("1") => true,
+                    Some("false") | Some("0") | None => false,
+                    Some(_) => return Err(invalid_value()),
+                };
+                metadata.insert("starred".to_string(), starred.to_json());
+            },
+            Xattr::Property(ref key) => {
+                let mut properties = BTreeMap::new();
+                properties.insert(key.clone(), value.map(|v| v.to_json()).unwrap_or(Json::Null));
+                metadata.insert("properties".to_string(), Json::Object(properties));
+            },
+            _ => return Err(DriveError {
+                kind: DriveErrorType::ReadOnlyXattr,
+                response: None,
+            }),
+        }
+
+        Ok(metadata)
+    }
+}
+
+fn invalid_value() -> DriveError {
+    DriveError {
+        kind: DriveErrorType::InvalidXattrValue,
+        response: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+    use super::*;
+
+    #[test]
+    fn drive_attributes() {
+        assert_eq!(Xattr::from_name("user.drive.id"), Some(Xattr::Id));
+        assert_eq!(Xattr::from_name("user.drive.mimetype"), Some(Xattr::MimeType));
+        assert_eq!(Xattr::from_name("user.drive.md5"), Some(Xattr::Md5));
+        assert_eq!(Xattr::from_name("user.drive.webViewLink"), Some(Xattr::WebViewLink));
+        assert_eq!(Xattr::from_name("user.drive.owners"), Some(Xattr::Owners));
+        assert_eq!(Xattr::from_name("user.drive.version"), Some(Xattr::Version));
+        assert_eq!(Xattr::from_name("user.drive.description"), Some(Xattr::Description));
+        assert_eq!(Xattr::from_name("user.drive.starred"), Some(Xattr::Starred));
+        assert_eq!(Xattr::from_name("user.drive.permissions"), Some(Xattr::Permissions));
+    }
+
+    #[test]
+    fn unknown_drive_attribute() {
+        // not stored as a property, which would shadow a field added later
+        assert_eq!(Xattr::from_name("user.drive.colour"), None);
+    }
+
+    #[test]
+    fn user_attributes_are_properties() {
+        assert_eq!(Xattr::from_name("user.origin"), Some(Xattr::Property("origin".to_string())));
+        assert_eq!(Xattr::from_name("user.driver.x"), Some(Xattr::Property("driver.x".to_string())));
+    }
+
+    #[test]
+    fn other_namespaces() {
+        assert_eq!(Xattr::from_name("user."), None);
+        assert_eq!(Xattr::from_name("security.selinux"), None);
+        assert_eq!(Xattr::from_name("trusted.drive.id"), None);
+    }
+
+    #[test]
+    fn only_some_are_writable() {
+        assert!(Xattr::Description.is_writable());
+        assert!(Xattr::Starred.is_writable());
+        assert!(Xattr::Property("origin".to_string()).is_writable());
+        assert!(!Xattr::Id.is_writable());
+        assert!(!Xattr::Md5.is_writable());
+        assert!(!Xattr::Permissions.is_writable());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for xattr in vec![Xattr::Id, Xattr::MimeType, Xattr::Md5, Xattr::WebViewLink, Xattr::Owners, Xattr::Version
+                         , Xattr::Description, Xattr::Starred, Xattr::Permissions, Xattr::Property("origin".to_string())] {
+            assert_eq!(Xattr::from_name(&xattr.name()), Some(xattr));
+        }
+    }
+
+    #[test]
+    fn all_attributes_of_a_file() {
+        let fr: DriveFileResponse = json::decode(r#"{"kind": "drive#file", "id": "abc", "name": "report.txt",
+                                                    "mimeType": "text/plain", "parents": ["root"],
+                                                    "md5Checksum": "d41d8cd98f00b204e9800998ecf8427e", "starred": false,
+                                                    "properties": {"origin": "scanner", "drive.id": "shadowed"}}"#).unwrap();
+        let names: Vec<String> = Xattr::all(&fr).iter().map(|xattr| xattr.name()).collect();
+        assert_eq!(names, vec!["user.drive.id", "user.drive.mimetype", "user.drive.md5", "user.drive.starred"
+                              , "user.drive.permissions", "user.origin"]);
+    }
+}